@@ -0,0 +1,178 @@
+use anyhow::{Context, Result};
+use crate::events;
+use crate::util::{path_exists, sha256_sum};
+use indicatif::HumanBytes;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{File, create_dir_all, read_dir, read_to_string, remove_file, write};
+use std::time::SystemTime;
+
+// Default location of the archive cache, e.g. ~/.cache/lcmodmanager
+pub fn default_cache_dir() -> String {
+    match dirs::cache_dir() {
+        Some(dir) => dir.join("lcmodmanager").display().to_string(),
+        None => "./lc/cache".to_string()
+    }
+}
+
+// What an archive in the cache was downloaded as, purely informational.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Label {
+    pub identifier: String,
+    pub version: String
+}
+
+pub struct Entry {
+    pub sha256: String,
+    pub size: u64,
+    pub used: SystemTime,
+    pub label: Option<Label>
+}
+
+// Plugin archives stored under their sha256, so any version that was downloaded once can be reinstalled offline.
+pub struct Cache {
+    pub dir: String
+}
+
+impl Cache {
+    pub fn open(dir: &str) -> Result<Cache> {
+        create_dir_all(format!("{0}/archives", dir)).with_context(|| format!("Could not create cache directory: {0}", dir))?;
+        Ok(Cache { dir: dir.to_string() })
+    }
+
+    // Where the archive with this checksum is (or would be) stored.
+    pub fn path(&self, sha256: &str) -> String {
+        format!("{0}/archives/{1}.tar.gz", self.dir, sha256)
+    }
+
+    fn index_path(&self) -> String {
+        format!("{0}/index.json", self.dir)
+    }
+
+    fn index(&self) -> BTreeMap<String, Label> {
+        read_to_string(self.index_path()).ok()
+            .and_then(|index| serde_json::from_str(&index).ok())
+            .unwrap_or_default()
+    }
+
+    fn save_index(&self, index: &BTreeMap<String, Label>) -> Result<()> {
+        let index = serde_json::to_string_pretty(index).context("Could not serialize cache index")?;
+        write(self.index_path(), index).context("Could not write cache index")
+    }
+
+    // Remember what a newly downloaded archive was.
+    pub fn label(&self, sha256: &str, label: Label) -> Result<()> {
+        let mut index = self.index();
+        index.insert(sha256.to_string(), label);
+        self.save_index(&index)
+    }
+
+    // True if a verified copy of the archive is cached. Corrupt copies are removed so they get downloaded again.
+    pub fn fetch(&self, sha256: &str) -> Result<bool> {
+        let path = self.path(sha256);
+        if !path_exists(&path) {
+            return Ok(false);
+        }
+
+        if sha256_sum(&path)? != sha256 {
+            events::warn(&format!("cached archive {0} is corrupt, downloading it again", sha256));
+            remove_file(&path).with_context(|| format!("Could not remove {0}", path))?;
+            return Ok(false);
+        }
+
+        // Mark it as recently used so pruning keeps it.
+        File::options().append(true).open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+            .with_context(|| format!("Could not update {0}", path))?;
+        Ok(true)
+    }
+
+    // Every archive in the cache, least recently used first.
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let index = self.index();
+        let mut entries: Vec<Entry> = vec![];
+        for entry in read_dir(format!("{0}/archives", self.dir)).context("Could not read cache directory")? {
+            let entry = entry.context("Could not read cache entry")?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some(sha256) = name.strip_suffix(".tar.gz") else { continue };
+            let meta = entry.metadata().context("Could not read cache entry")?;
+            entries.push(Entry {
+                sha256: sha256.to_string(),
+                size: meta.len(),
+                used: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                label: index.get(sha256).cloned()
+            });
+        }
+        entries.sort_by_key(|entry| entry.used);
+        Ok(entries)
+    }
+
+    pub fn list(&self) -> Result<()> {
+        let entries = self.entries()?;
+        let total: u64 = entries.iter().map(|entry| entry.size).sum();
+        for entry in entries.iter().rev() {
+            println!("{0}  {1:>10}  {2}", entry.sha256, HumanBytes(entry.size).to_string(), describe(entry));
+        }
+        println!("{0} archives, {1} in {2}", entries.len(), HumanBytes(total), self.dir);
+        Ok(())
+    }
+
+    // Recompute every checksum, removing archives that don't match their name. Returns how many were removed.
+    pub fn verify(&self) -> Result<usize> {
+        let mut removed = 0;
+        for entry in self.entries()? {
+            let path = self.path(&entry.sha256);
+            if sha256_sum(&path)? == entry.sha256 {
+                println!("ok       {0}  {1}", entry.sha256, describe(&entry));
+            } else {
+                println!("corrupt  {0}  {1}", entry.sha256, describe(&entry));
+                remove_file(&path).with_context(|| format!("Could not remove {0}", path))?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    // Remove the least recently used archives until the cache fits in `max_size` bytes.
+    pub fn prune(&self, max_size: u64) -> Result<u64> {
+        let entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+        let mut freed: u64 = 0;
+        let mut index = self.index();
+
+        for entry in entries {
+            if total <= max_size {
+                break;
+            }
+            let path = self.path(&entry.sha256);
+            remove_file(&path).with_context(|| format!("Could not remove {0}", path))?;
+            index.remove(&entry.sha256);
+            total -= entry.size;
+            freed += entry.size;
+        }
+
+        self.save_index(&index)?;
+        Ok(freed)
+    }
+}
+
+fn describe(entry: &Entry) -> String {
+    match &entry.label {
+        Some(label) => format!("{0} {1}", label.identifier, label.version),
+        None => "unknown".to_string()
+    }
+}
+
+// Parses sizes such as 500M, 500MB or 2GiB (powers of 1024), a bare number is bytes.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let upper = size.trim().to_uppercase();
+    let upper = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (number, multiplier) = match upper.chars().last() {
+        Some('K') => (&upper[..upper.len() - 1], 1 << 10),
+        Some('M') => (&upper[..upper.len() - 1], 1 << 20),
+        Some('G') => (&upper[..upper.len() - 1], 1 << 30),
+        _ => (upper, 1)
+    };
+    let number: u64 = number.trim().parse().with_context(|| format!("Invalid size: {0}", size))?;
+    Ok(number * multiplier)
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+// A setting's section and key.
+type Setting = (String, String);
+
+// A `key = value` line in a BepInEx config file, keyed by its section and key.
+fn parse_line<'a>(section: &str, line: &'a str) -> Option<(Setting, &'a str)> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with('[') {
+        return None;
+    }
+    let (key, value) = trimmed.split_once('=')?;
+    Some(((section.to_string(), key.trim().to_string()), value.trim()))
+}
+
+// The section a `[Section]` header line starts, if it is one.
+fn parse_section(line: &str) -> Option<&str> {
+    line.trim().strip_prefix('[')?.strip_suffix(']')
+}
+
+// Every setting in a config file with its value, and the order they appear in.
+fn parse(contents: &str) -> (HashMap<Setting, String>, Vec<Setting>) {
+    let mut values = HashMap::new();
+    let mut order = vec![];
+    let mut section = String::new();
+    for line in contents.lines() {
+        if let Some(name) = parse_section(line) {
+            section = name.to_string();
+        } else if let Some((setting, value)) = parse_line(&section, line) {
+            order.push(setting.clone());
+            values.insert(setting, value.to_string());
+        }
+    }
+    (values, order)
+}
+
+// Three-way merge of BepInEx config files. `base` is the default the user started from, `current` is the user's copy and `new` is the server's new default.
+// The server's layout and comments are kept, settings the user changed keep the user's value and settings the user added are kept.
+// Returns the merged file and the settings both sides changed, where the user's value wins.
+pub fn merge(base: &str, current: &str, new: &str) -> (String, Vec<String>) {
+    let (base_values, _) = parse(base);
+    let (current_values, current_order) = parse(current);
+    let (new_values, _) = parse(new);
+    let mut conflicts: Vec<String> = vec![];
+
+    let mut lines: Vec<String> = vec![];
+    // Where settings can be added to each section, just after its last non-empty line.
+    let mut section_ends: Vec<(String, usize)> = vec![];
+    let mut section = String::new();
+    for line in new.lines() {
+        if let Some(name) = parse_section(line) {
+            section = name.to_string();
+        }
+
+        let mut merged = line.to_string();
+        if let Some((setting, new_value)) = parse_line(&section, line) {
+            let base_value = base_values.get(&setting);
+            if let Some(current_value) = current_values.get(&setting) {
+                // A setting missing from the old default was most likely written by the game, so the server's value wins.
+                let user_changed = base_value.map(|base| base != current_value).unwrap_or(false);
+                let server_changed = base_value.map(|base| base != new_value).unwrap_or(false);
+                if user_changed && current_value != new_value {
+                    if server_changed {
+                        conflicts.push(format!("[{0}] {1}", setting.0, setting.1));
+                    }
+                    let indent = &line[..line.len() - line.trim_start().len()];
+                    merged = format!("{0}{1} = {2}", indent, setting.1, current_value);
+                }
+            }
+        }
+
+        lines.push(merged);
+        if !line.trim().is_empty() {
+            match section_ends.iter_mut().find(|(name, _)| *name == section) {
+                Some((_, end)) => *end = lines.len(),
+                None => section_ends.push((section.clone(), lines.len()))
+            }
+        }
+    }
+
+    // Settings only the user added, which the server neither had nor has.
+    let mut added: Vec<(usize, String)> = vec![];
+    for setting in current_order {
+        if base_values.contains_key(&setting) || new_values.contains_key(&setting) {
+            continue;
+        }
+        let line = format!("{0} = {1}", setting.1, current_values[&setting]);
+        match section_ends.iter().find(|(name, _)| *name == setting.0) {
+            Some((_, end)) => added.push((*end, line)),
+            None => {
+                lines.push(String::new());
+                lines.push(format!("[{0}]", setting.0));
+                lines.push(line);
+                section_ends.push((setting.0.clone(), lines.len()));
+            }
+        }
+    }
+    // Insert from the bottom up so earlier positions stay valid, keeping the user's order within a section.
+    added.sort_by_key(|(end, _)| *end);
+    for (end, line) in added.into_iter().rev() {
+        lines.insert(end, line);
+    }
+
+    let mut merged = lines.join("\n");
+    if new.ends_with('\n') {
+        merged.push('\n');
+    }
+    (merged, conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_user_changed() {
+        let (merged, conflicts) = merge("[General]\na = 1\nb = 2\n", "[General]\na = 5\nb = 2\n", "[General]\na = 1\nb = 3\n");
+        assert_eq!(merged, "[General]\na = 5\nb = 3\n");
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn both_changed() {
+        let (merged, conflicts) = merge("[General]\na = 1\n", "[General]\na = 5\n", "[General]\na = 7\n");
+        assert_eq!(merged, "[General]\na = 5\n");
+        assert_eq!(conflicts, vec!["[General] a".to_string()]);
+    }
+
+    #[test]
+    fn user_added_keys() {
+        let base = "[General]\na = 1\n\n[Other]\nz = 0\n";
+        let (merged, conflicts) = merge(base, "[General]\na = 1\nc = 9\n\n[Other]\nz = 0\n", base);
+        assert_eq!(merged, "[General]\na = 1\nc = 9\n\n[Other]\nz = 0\n");
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn new_sections() {
+        let (merged, _) = merge("[General]\na = 1\n", "[General]\na = 1\n\n[Mine]\nm = 4\n", "[General]\na = 1\n\n[Added]\nx = 1\n");
+        assert_eq!(merged, "[General]\na = 1\n\n[Added]\nx = 1\n\n[Mine]\nm = 4\n");
+    }
+
+    #[test]
+    fn without_a_base() {
+        // What a sync does when the default the player started from wasn't recorded.
+        let new = "[General]\nj = 3\nn = 9\n";
+        let (merged, _) = merge(new, "[General]\nj = 5\n", new);
+        assert_eq!(merged, "[General]\nj = 5\nn = 9\n");
+    }
+}
@@ -0,0 +1,425 @@
+use anyhow::{Context, Result};
+use crate::cache::{default_cache_dir, parse_size};
+use crate::discover::{installs, is_install, pick};
+use crate::plan::DowngradePolicy;
+use crate::self_update::SelfUpdatePolicy;
+use crate::sign::parse_keys;
+use crate::steam::Bootstrap;
+use crate::util::{expand_home, path_exists, LCError};
+use clap::ValueEnum;
+use dotenvy_macro::dotenv;
+use serde::Deserialize;
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Number of plugins downloaded at once unless configured otherwise
+const DEFAULT_JOBS: &str = "4";
+
+// Largest the archive cache may grow to after a sync unless configured otherwise
+const DEFAULT_CACHE_LIMIT: &str = "2G";
+
+// Downgrades are applied like any other change unless configured otherwise
+const DEFAULT_DOWNGRADE: &str = "allow";
+
+// Syncs offer newer lcmodmanager releases unless configured otherwise
+const DEFAULT_SELF_UPDATE: &str = "ask";
+
+// A fresh BepInEx install is finished by launching the game once unless configured otherwise
+const DEFAULT_BEPINEX_BOOTSTRAP: &str = "launch";
+
+// Seconds to wait for that first launch to set up BepInEx unless configured otherwise
+const DEFAULT_BEPINEX_TIMEOUT: &str = "300";
+
+// Prefix for environment variables that override the config file, e.g. LCMODMANAGER_LCDOWNLOAD
+const ENV_PREFIX: &str = "LCMODMANAGER_";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Platform {
+    Windows,
+    Linux,
+    Flatpak
+}
+
+// Where a resolved setting came from, lowest to highest priority.
+#[derive(Clone)]
+pub enum Source {
+    Default,
+    Discovered,
+    File(String),
+    Profile(String),
+    Env(String),
+    Cli(String)
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "compiled-in default"),
+            Source::Discovered => write!(f, "a Steam library"),
+            Source::File(path) => write!(f, "config file ({0})", path),
+            Source::Profile(name) => write!(f, "profile ({0})", name),
+            Source::Env(var) => write!(f, "environment ({0})", var),
+            Source::Cli(flag) => write!(f, "command line ({0})", flag)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Setting {
+    pub value: String,
+    pub source: Source
+}
+
+impl Setting {
+    fn default(value: &str) -> Setting {
+        Setting { value: value.to_string(), source: Source::Default }
+    }
+
+    // Replace the value if a higher priority layer provides one.
+    fn layer(&mut self, value: Option<String>, source: Source) {
+        if let Some(value) = value {
+            self.value = value;
+            self.source = source;
+        }
+    }
+}
+
+// Mirrors the keys of the compiled-in .env, all optional.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    lc_download: Option<String>,
+    sources: Option<Vec<String>>,
+    bepinex_download: Option<String>,
+    bepinex_sha256: Option<String>,
+    trusted_keys: Option<Vec<String>>,
+    windows_lc_path: Option<String>,
+    linux_lc_path: Option<String>,
+    flatpak_lc_path: Option<String>,
+    windows_steam_path: Option<String>,
+    linux_steam_path: Option<String>,
+    flatpak_path: Option<String>,
+    jobs: Option<usize>,
+    cache_dir: Option<String>,
+    cache_limit: Option<String>,
+    downgrade: Option<String>,
+    self_update: Option<String>,
+    bepinex_bootstrap: Option<String>,
+    bepinex_timeout: Option<u64>
+}
+
+// Values given on the command line, these win over everything else.
+#[derive(Default)]
+pub struct CliOverrides {
+    pub lc_download: Option<String>,
+    pub sources: Option<String>,
+    pub bepinex_download: Option<String>,
+    pub bepinex_sha256: Option<String>,
+    pub trusted_keys: Option<String>,
+    pub lethal_company_path: Option<String>,
+    pub steam_path: Option<String>,
+    pub jobs: Option<usize>,
+    pub cache_dir: Option<String>,
+    pub downgrade: Option<String>,
+    pub bepinex_bootstrap: Option<String>,
+    pub bepinex_timeout: Option<u64>
+}
+
+pub struct Config {
+    pub lc_download: Setting,
+    pub sources: Setting,
+    pub bepinex_download: Setting,
+    pub bepinex_sha256: Setting,
+    pub trusted_keys: Setting,
+    pub windows_lc_path: Setting,
+    pub linux_lc_path: Setting,
+    pub flatpak_lc_path: Setting,
+    pub windows_steam_path: Setting,
+    pub linux_steam_path: Setting,
+    pub flatpak_path: Setting,
+    pub jobs: Setting,
+    pub cache_dir: Setting,
+    pub cache_limit: Setting,
+    pub downgrade: Setting,
+    pub self_update: Setting,
+    pub bepinex_bootstrap: Setting,
+    pub bepinex_timeout: Setting
+}
+
+// Default location of the config file, e.g. ~/.config/lcmodmanager/config.toml
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("lcmodmanager").join("config.toml"))
+}
+
+impl Config {
+    // The compiled-in .env values, used whenever nothing else is set.
+    fn defaults() -> Config {
+        Config {
+            lc_download: Setting::default(dotenv!("LCDOWNLOAD")),
+            sources: Setting::default(""),
+            bepinex_download: Setting::default(dotenv!("BEPINEXDOWNLOAD")),
+            bepinex_sha256: Setting::default(dotenv!("BEPINEXSHA256")),
+            trusted_keys: Setting::default(dotenv!("TRUSTEDKEYS")),
+            windows_lc_path: Setting::default(dotenv!("WINDOWSLCPATH")),
+            linux_lc_path: Setting::default(dotenv!("LINUXLCPATH")),
+            flatpak_lc_path: Setting::default(dotenv!("FLATPAKLCPATH")),
+            windows_steam_path: Setting::default(dotenv!("WINDOWSTEAMPATH")),
+            linux_steam_path: Setting::default(dotenv!("LINUXSTEAMPATH")),
+            flatpak_path: Setting::default(dotenv!("FLATPAKPATH")),
+            jobs: Setting::default(DEFAULT_JOBS),
+            cache_dir: Setting::default(&default_cache_dir()),
+            cache_limit: Setting::default(DEFAULT_CACHE_LIMIT),
+            downgrade: Setting::default(DEFAULT_DOWNGRADE),
+            self_update: Setting::default(DEFAULT_SELF_UPDATE),
+            bepinex_bootstrap: Setting::default(DEFAULT_BEPINEX_BOOTSTRAP),
+            bepinex_timeout: Setting::default(DEFAULT_BEPINEX_TIMEOUT)
+        }
+    }
+
+    // Every setting alongside the .env name it corresponds to.
+    pub fn fields(&self) -> [(&'static str, &Setting); 18] {
+        [
+            ("LCDOWNLOAD", &self.lc_download),
+            ("SOURCES", &self.sources),
+            ("BEPINEXDOWNLOAD", &self.bepinex_download),
+            ("BEPINEXSHA256", &self.bepinex_sha256),
+            ("TRUSTEDKEYS", &self.trusted_keys),
+            ("WINDOWSLCPATH", &self.windows_lc_path),
+            ("LINUXLCPATH", &self.linux_lc_path),
+            ("FLATPAKLCPATH", &self.flatpak_lc_path),
+            ("WINDOWSTEAMPATH", &self.windows_steam_path),
+            ("LINUXSTEAMPATH", &self.linux_steam_path),
+            ("FLATPAKPATH", &self.flatpak_path),
+            ("JOBS", &self.jobs),
+            ("CACHEDIR", &self.cache_dir),
+            ("CACHELIMIT", &self.cache_limit),
+            ("DOWNGRADE", &self.downgrade),
+            ("SELFUPDATE", &self.self_update),
+            ("BEPINEXBOOTSTRAP", &self.bepinex_bootstrap),
+            ("BEPINEXTIMEOUT", &self.bepinex_timeout)
+        ]
+    }
+
+    fn fields_mut(&mut self) -> [(&'static str, &mut Setting); 18] {
+        [
+            ("LCDOWNLOAD", &mut self.lc_download),
+            ("SOURCES", &mut self.sources),
+            ("BEPINEXDOWNLOAD", &mut self.bepinex_download),
+            ("BEPINEXSHA256", &mut self.bepinex_sha256),
+            ("TRUSTEDKEYS", &mut self.trusted_keys),
+            ("WINDOWSLCPATH", &mut self.windows_lc_path),
+            ("LINUXLCPATH", &mut self.linux_lc_path),
+            ("FLATPAKLCPATH", &mut self.flatpak_lc_path),
+            ("WINDOWSTEAMPATH", &mut self.windows_steam_path),
+            ("LINUXSTEAMPATH", &mut self.linux_steam_path),
+            ("FLATPAKPATH", &mut self.flatpak_path),
+            ("JOBS", &mut self.jobs),
+            ("CACHEDIR", &mut self.cache_dir),
+            ("CACHELIMIT", &mut self.cache_limit),
+            ("DOWNGRADE", &mut self.downgrade),
+            ("SELFUPDATE", &mut self.self_update),
+            ("BEPINEXBOOTSTRAP", &mut self.bepinex_bootstrap),
+            ("BEPINEXTIMEOUT", &mut self.bepinex_timeout)
+        ]
+    }
+
+    // Resolve the configuration: compiled-in defaults, then the config file, then environment variables, then the command line.
+    pub fn load(config_path: Option<&str>, platform: Platform, cli: CliOverrides) -> Result<Config> {
+        let mut config = Config::defaults();
+
+        // An explicitly given config file has to exist, the default one is optional.
+        let path = match config_path {
+            Some(path) => Some(PathBuf::from(path)),
+            None => default_config_path().filter(|path| path.exists())
+        };
+
+        if let Some(path) = path {
+            let path_str = path.display().to_string();
+            if !path_exists(&path_str) {
+                return Err(LCError::Usage(format!("Config file does not exist: {0}", path_str)).into());
+            }
+            let contents = read_to_string(&path).with_context(|| format!("Could not read config file: {0}", path_str))?;
+            let file: ConfigFile = toml::from_str(&contents)
+                .map_err(|err| LCError::Usage(format!("Could not parse config file {0}: {1}", path_str, err)))?;
+            config.apply_file(file, &path_str);
+        }
+
+        for (name, setting) in config.fields_mut() {
+            let var = format!("{0}{1}", ENV_PREFIX, name);
+            if let Ok(value) = std::env::var(&var) {
+                setting.layer(Some(value), Source::Env(var));
+            }
+        }
+
+        // --lc-download on its own means only that server.
+        if cli.lc_download.is_some() && cli.sources.is_none() {
+            config.sources.layer(Some(String::new()), Source::Cli("--lc-download".to_string()));
+        }
+        config.lc_download.layer(cli.lc_download, Source::Cli("--lc-download".to_string()));
+        config.sources.layer(cli.sources, Source::Cli("--source".to_string()));
+        config.bepinex_download.layer(cli.bepinex_download, Source::Cli("--bepinex-download".to_string()));
+        config.bepinex_sha256.layer(cli.bepinex_sha256, Source::Cli("--bepinex-sha256".to_string()));
+        config.trusted_keys.layer(cli.trusted_keys, Source::Cli("--trusted-keys".to_string()));
+        config.lc_path_setting_mut(platform).layer(cli.lethal_company_path, Source::Cli("--lethal-company-path".to_string()));
+        config.steam_path_setting_mut(platform).layer(cli.steam_path, Source::Cli("--steam-path".to_string()));
+        config.jobs.layer(cli.jobs.map(|jobs| jobs.to_string()), Source::Cli("--jobs".to_string()));
+        config.cache_dir.layer(cli.cache_dir, Source::Cli("--cache-dir".to_string()));
+        config.downgrade.layer(cli.downgrade, Source::Cli("--downgrade".to_string()));
+        config.bepinex_bootstrap.layer(cli.bepinex_bootstrap, Source::Cli("--bepinex-bootstrap".to_string()));
+        config.bepinex_timeout.layer(cli.bepinex_timeout.map(|timeout| timeout.to_string()), Source::Cli("--bepinex-timeout".to_string()));
+
+        Ok(config)
+    }
+
+    fn apply_file(&mut self, file: ConfigFile, path: &str) {
+        let source = || Source::File(path.to_string());
+        self.lc_download.layer(file.lc_download, source());
+        self.sources.layer(file.sources.map(|sources| sources.join(",")), source());
+        self.bepinex_download.layer(file.bepinex_download, source());
+        self.bepinex_sha256.layer(file.bepinex_sha256, source());
+        self.trusted_keys.layer(file.trusted_keys.map(|keys| keys.join(",")), source());
+        self.windows_lc_path.layer(file.windows_lc_path, source());
+        self.linux_lc_path.layer(file.linux_lc_path, source());
+        self.flatpak_lc_path.layer(file.flatpak_lc_path, source());
+        self.windows_steam_path.layer(file.windows_steam_path, source());
+        self.linux_steam_path.layer(file.linux_steam_path, source());
+        self.flatpak_path.layer(file.flatpak_path, source());
+        self.jobs.layer(file.jobs.map(|jobs| jobs.to_string()), source());
+        self.cache_dir.layer(file.cache_dir, source());
+        self.cache_limit.layer(file.cache_limit, source());
+        self.downgrade.layer(file.downgrade, source());
+        self.self_update.layer(file.self_update, source());
+        self.bepinex_bootstrap.layer(file.bepinex_bootstrap, source());
+        self.bepinex_timeout.layer(file.bepinex_timeout.map(|timeout| timeout.to_string()), source());
+    }
+
+    fn lc_path_setting_mut(&mut self, platform: Platform) -> &mut Setting {
+        match platform {
+            Platform::Windows => &mut self.windows_lc_path,
+            Platform::Linux => &mut self.linux_lc_path,
+            Platform::Flatpak => &mut self.flatpak_lc_path
+        }
+    }
+
+    fn steam_path_setting_mut(&mut self, platform: Platform) -> &mut Setting {
+        match platform {
+            Platform::Windows => &mut self.windows_steam_path,
+            Platform::Linux => &mut self.linux_steam_path,
+            Platform::Flatpak => &mut self.flatpak_path
+        }
+    }
+
+    // The Lethal Company path for the given platform, with ~ expanded.
+    pub fn lc_path(&self, platform: Platform) -> Result<String> {
+        let setting = match platform {
+            Platform::Windows => &self.windows_lc_path,
+            Platform::Linux => &self.linux_lc_path,
+            Platform::Flatpak => &self.flatpak_lc_path
+        };
+        expand_home(&setting.value)
+    }
+
+    // Look for the game in Steam's libraries when its path wasn't configured and the default isn't an install.
+    pub fn discover_lc_path(&mut self, platform: Platform, assume_yes: bool) -> Result<()> {
+        if !matches!(self.lc_path_setting_mut(platform).source, Source::Default) || is_install(&self.lc_path(platform)?) {
+            return Ok(());
+        }
+        let found = pick(installs(platform, &self.steam_path(platform))?, assume_yes)?;
+        self.lc_path_setting_mut(platform).layer(found, Source::Discovered);
+        Ok(())
+    }
+
+    // The command used to launch steam for the given platform.
+    pub fn steam_path(&self, platform: Platform) -> String {
+        match platform {
+            Platform::Windows => self.windows_steam_path.value.clone(),
+            Platform::Linux => self.linux_steam_path.value.clone(),
+            Platform::Flatpak => self.flatpak_path.value.clone()
+        }
+    }
+
+    // How many plugins to download at once.
+    pub fn jobs(&self) -> Result<usize> {
+        match self.jobs.value.parse::<usize>() {
+            Ok(jobs) if jobs > 0 => Ok(jobs),
+            _ => Err(LCError::Usage(format!("JOBS must be a positive number, got \"{0}\" from {1}", self.jobs.value, self.jobs.source)).into())
+        }
+    }
+
+    // Modpack servers to sync from, highest priority first. Without any, LCDOWNLOAD is the only source.
+    pub fn sources(&self) -> Vec<String> {
+        let sources: Vec<String> = self.sources.value.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|source| !source.is_empty())
+            .map(|source| source.trim_end_matches('/').to_string())
+            .collect();
+        if sources.is_empty() {
+            vec![self.lc_download.value.clone()]
+        } else {
+            sources
+        }
+    }
+
+    // Base64 ed25519 public keys that plugins.json must be signed by.
+    pub fn trusted_keys(&self) -> Vec<String> {
+        parse_keys(&self.trusted_keys.value)
+    }
+
+    // The archive cache directory, with ~ expanded.
+    pub fn cache_dir(&self) -> Result<String> {
+        expand_home(&self.cache_dir.value)
+    }
+
+    // The largest the archive cache may grow to, in bytes.
+    pub fn cache_limit(&self) -> Result<u64> {
+        parse_size(&self.cache_limit.value)
+            .map_err(|err| LCError::Usage(format!("Invalid CACHELIMIT from {0}: {1}", self.cache_limit.source, err)).into())
+    }
+
+    // What to do when the server rolls a plugin back to an older version.
+    pub fn downgrade(&self) -> Result<DowngradePolicy> {
+        DowngradePolicy::from_str(&self.downgrade.value, true)
+            .map_err(|_| LCError::Usage(format!("DOWNGRADE must be allow, ask or refuse, got \"{0}\" from {1}", self.downgrade.value, self.downgrade.source)).into())
+    }
+
+    // Whether a sync offers to update lcmodmanager when the modpack has a newer release.
+    pub fn self_update(&self) -> Result<SelfUpdatePolicy> {
+        SelfUpdatePolicy::from_str(&self.self_update.value, true)
+            .map_err(|_| LCError::Usage(format!("SELFUPDATE must be ask or never, got \"{0}\" from {1}", self.self_update.value, self.self_update.source)).into())
+    }
+
+    // How a fresh BepInEx install is finished.
+    pub fn bepinex_bootstrap(&self) -> Result<Bootstrap> {
+        Bootstrap::from_str(&self.bepinex_bootstrap.value, true)
+            .map_err(|_| LCError::Usage(format!("BEPINEXBOOTSTRAP must be launch or layout, got \"{0}\" from {1}", self.bepinex_bootstrap.value, self.bepinex_bootstrap.source)).into())
+    }
+
+    // How long to wait for the game's first launch to set up BepInEx.
+    pub fn bepinex_timeout(&self) -> Result<Duration> {
+        match self.bepinex_timeout.value.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+            _ => Err(LCError::Usage(format!("BEPINEXTIMEOUT must be a positive number of seconds, got \"{0}\" from {1}", self.bepinex_timeout.value, self.bepinex_timeout.source)).into())
+        }
+    }
+
+    // Sync from the active profile's server unless the environment or command line chose where to sync from.
+    pub fn apply_profile(&mut self, name: &str, lc_download: &str) {
+        if matches!(self.lc_download.source, Source::Default | Source::File(_)) {
+            self.lc_download.layer(Some(lc_download.to_string()), Source::Profile(name.to_string()));
+        }
+        if matches!(self.sources.source, Source::Default | Source::File(_)) {
+            self.sources.layer(Some(String::new()), Source::Profile(name.to_string()));
+        }
+    }
+
+    // Print every resolved value and where it came from.
+
+    pub fn show(&self) {
+        for (name, setting) in self.fields() {
+            let value = if setting.value.is_empty() { "<unset>" } else { &setting.value };
+            println!("{0:<16} = {1}", name, value);
+            println!("{0:<16}   from {1}", "", setting.source);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::Result;
+use crate::config::Platform;
+use crate::events;
+use crate::util::{expand_home, path_exists, prompt, LCError};
+use crate::vdf::{load, Vdf};
+use std::fs::canonicalize;
+
+// Lethal Company's Steam app id.
+pub const APP_ID: &str = "1966720";
+
+// Where Steam may be installed for the platform, whether or not it is.
+pub fn steam_roots(platform: Platform, steam_path: &str) -> Result<Vec<String>> {
+    match platform {
+        Platform::Windows => {
+            let mut roots = vec!["C:/Program Files (x86)/Steam".to_string(), "C:/Program Files/Steam".to_string()];
+            // The configured Steam.exe lives in the Steam root.
+            if let Some((root, _)) = steam_path.rsplit_once(['/', '\\']) {
+                roots.insert(0, root.to_string());
+            }
+            Ok(roots)
+        }
+        Platform::Linux => vec![
+            "~/.steam/steam",
+            "~/.steam/root",
+            "~/.local/share/Steam",
+            "~/snap/steam/common/.local/share/Steam"
+        ].into_iter().map(expand_home).collect(),
+        Platform::Flatpak => vec![
+            "~/.var/app/com.valvesoftware.Steam/data/Steam",
+            "~/.var/app/com.valvesoftware.Steam/.local/share/Steam"
+        ].into_iter().map(expand_home).collect()
+    }
+}
+
+// Every library folder of a Steam root, including the root itself.
+fn library_folders(root: &str) -> Vec<String> {
+    let mut libraries = vec![root.to_string()];
+    let vdf = load(&format!("{0}/steamapps/libraryfolders.vdf", root)).ok()
+        .or_else(|| load(&format!("{0}/config/libraryfolders.vdf", root)).ok());
+    let Some(folders) = vdf.as_ref().and_then(|vdf| vdf.get("libraryfolders")) else { return libraries };
+    let Vdf::Object(entries) = folders else { return libraries };
+    for (key, entry) in entries {
+        // Older Steam versions list each library's path directly under its number, newer ones in an object with a "path".
+        if !key.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        if let Some(path) = entry.value().or_else(|| entry.get("path").and_then(Vdf::value)) {
+            libraries.push(path.to_string());
+        }
+    }
+    libraries
+}
+
+// True if the folder holds a Lethal Company install.
+pub fn is_install(path: &str) -> bool {
+    path_exists(&format!("{0}/Lethal Company.exe", path))
+}
+
+// Every Lethal Company install in the platform's Steam libraries.
+pub fn installs(platform: Platform, steam_path: &str) -> Result<Vec<String>> {
+    let mut installs: Vec<String> = vec![];
+    let mut seen: Vec<std::path::PathBuf> = vec![];
+    for root in steam_roots(platform, steam_path)? {
+        for library in library_folders(&root) {
+            let Ok(manifest) = load(&format!("{0}/steamapps/appmanifest_{1}.acf", library, APP_ID)) else { continue };
+            let Some(install_dir) = manifest.get("AppState").and_then(|state| state.get("installdir")).and_then(Vdf::value) else { continue };
+            let install = format!("{0}/steamapps/common/{1}", library, install_dir);
+            if !is_install(&install) {
+                continue;
+            }
+            // ~/.steam/steam is usually a link to one of the other roots.
+            let Ok(canonical) = canonicalize(&install) else { continue };
+            if !seen.contains(&canonical) {
+                seen.push(canonical);
+                installs.push(install);
+            }
+        }
+    }
+    Ok(installs)
+}
+
+// Choose between the installs found, asking the player when there is more than one.
+pub fn pick(installs: Vec<String>, assume_yes: bool) -> Result<Option<String>> {
+    if installs.len() <= 1 {
+        return Ok(installs.into_iter().next());
+    }
+
+    let list: Vec<String> = installs.iter().enumerate().map(|(index, install)| format!("  {0}) {1}", index + 1, install)).collect();
+    if assume_yes {
+        return Err(LCError::Usage(format!("Found several Lethal Company installs, choose one with --lethal-company-path:\n{0}", list.join("\n"))).into());
+    }
+
+    events::info(&format!("Found several Lethal Company installs:\n{0}", list.join("\n")));
+    loop {
+        let Some(answer) = prompt(&format!("Which one should be used? [1-{0}]", installs.len()))? else {
+            return Err(LCError::Cancelled("No Lethal Company install was chosen".to_string()).into());
+        };
+        if let Ok(choice) = answer.parse::<usize>() {
+            if (1..=installs.len()).contains(&choice) {
+                return Ok(installs.into_iter().nth(choice - 1));
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{Context, Result};
+use crate::events::{self, Event};
+use crate::util::LCError;
+use data_encoding::HEXLOWER;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use reqwest::blocking::get;
+use ring::digest;
+use std::fs::{File, remove_file, rename};
+use std::io::{BufWriter, Read, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+// How often, in bytes, a download reports its progress as an event.
+const PROGRESS_STEP: u64 = 1024 * 1024;
+
+// A file to fetch and the checksum it must match.
+pub struct Download {
+    pub name: String,
+    pub url: String,
+    pub dest: String,
+    pub sha256: String
+}
+
+// Stream a download to disk, hashing it as it is written, and check the result against the expected checksum.
+fn fetch(download: &Download, bar: &ProgressBar, total: &ProgressBar) -> Result<()> {
+    let mut resp = get(&download.url).and_then(|resp| resp.error_for_status())
+        .map_err(|source| LCError::Network { url: download.url.clone(), source })
+        .with_context(|| format!("Could not get {0}", download.name))?;
+    let size = resp.content_length();
+    if let Some(length) = size {
+        bar.set_length(length);
+        total.inc_length(length);
+    }
+    events::emit(&Event::DownloadStarted { name: &download.name, size });
+
+    // Write to a partial file first so an interrupted download never looks complete.
+    let partial = format!("{0}.part", download.dest);
+    let file = File::create(&partial).with_context(|| format!("Could not write {0}", download.name))?;
+    let mut writer = BufWriter::new(file);
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut buffer = [0; 16384];
+    let mut downloaded: u64 = 0;
+
+    loop {
+        let count = resp.read(&mut buffer).with_context(|| format!("Could not read {0}", download.name))?;
+        if count == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..count]).with_context(|| format!("Could not write {0}", download.name))?;
+        context.update(&buffer[..count]);
+        bar.inc(count as u64);
+        total.inc(count as u64);
+        if (downloaded + count as u64) / PROGRESS_STEP != downloaded / PROGRESS_STEP {
+            events::emit(&Event::DownloadProgress { name: &download.name, downloaded: downloaded + count as u64, size });
+        }
+        downloaded += count as u64;
+    }
+    writer.flush().with_context(|| format!("Could not write {0}", download.name))?;
+
+    let sha256 = HEXLOWER.encode(context.finish().as_ref());
+    if sha256 != download.sha256 {
+        remove_file(&partial).with_context(|| format!("Could not remove {0}", partial))?;
+        return Err(LCError::CheckSumDiscrepency { name: download.name.clone(), expected: download.sha256.clone(), actual: sha256 }.into());
+    }
+
+    rename(&partial, &download.dest).with_context(|| format!("Could not write {0}", download.name))?;
+    events::emit(&Event::DownloadFinished { name: &download.name });
+    Ok(())
+}
+
+// Download every file with at most `jobs` running at once, showing a bar per file and one for the total.
+// Stops starting new downloads after the first failure and returns that error.
+pub fn download_all(downloads: &[Download], jobs: usize) -> Result<()> {
+    if downloads.is_empty() {
+        return Ok(());
+    }
+
+    // JSON output reports progress as events instead.
+    let progress = if events::json() { MultiProgress::with_draw_target(ProgressDrawTarget::hidden()) } else { MultiProgress::new() };
+    let bar_style = ProgressStyle::with_template("{prefix:>24} [{bar:30}] {bytes}/{total_bytes}")
+        .context("Invalid progress template")?
+        .progress_chars("=> ");
+    let total = progress.add(ProgressBar::new(0));
+    total.set_style(ProgressStyle::with_template("{prefix:>24} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec})")
+        .context("Invalid progress template")?
+        .progress_chars("=> "));
+    total.set_prefix(format!("total ({0} plugins)", downloads.len()));
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let first_error: Mutex<Option<anyhow::Error>> = Mutex::new(None);
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, downloads.len()) {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    if index >= downloads.len() || failed.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    let download = &downloads[index];
+                    let bar = progress.insert_before(&total, ProgressBar::new(0));
+                    bar.set_style(bar_style.clone());
+                    bar.set_prefix(download.name.clone());
+
+                    match fetch(download, &bar, &total) {
+                        Ok(()) => bar.finish_and_clear(),
+                        Err(err) => {
+                            bar.abandon();
+                            failed.store(true, Ordering::SeqCst);
+                            first_error.lock().unwrap().get_or_insert(err);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    total.finish_and_clear();
+    match first_error.into_inner().unwrap() {
+        Some(err) => Err(err),
+        None => Ok(())
+    }
+}
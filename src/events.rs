@@ -0,0 +1,71 @@
+use crate::plan::{OutputFormat, Plan};
+use serde::Serialize;
+use std::io::{stdout, Write};
+use std::sync::OnceLock;
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+// What happened during a sync. With --output json each one is written to stdout as a line of JSON for other tools to follow,
+// otherwise only messages are printed.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Message { level: Level, text: &'a str },
+    Plan(&'a Plan),
+    DownloadStarted { name: &'a str, size: Option<u64> },
+    DownloadProgress { name: &'a str, downloaded: u64, size: Option<u64> },
+    DownloadFinished { name: &'a str },
+    Installed { identifier: &'a str, version: &'a str },
+    Removed { identifier: &'a str, version: &'a str },
+    Summary { installed: usize, removed: usize, unchanged: usize },
+    Plugin { identifier: &'a str, version: &'a str, selection: &'a str, enabled: bool },
+    Error { message: &'a str, code: u8 }
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Info,
+    Warning
+}
+
+// Choose how events are reported, once at startup.
+pub fn init(format: OutputFormat) {
+    let _ = FORMAT.set(format);
+}
+
+// True if events are written as JSON lines, so nothing else may be printed to stdout.
+pub fn json() -> bool {
+    FORMAT.get() == Some(&OutputFormat::Json)
+}
+
+// Write a line to stdout. A closed stdout, e.g. piped into head, has no one left to read it, so that isn't an error.
+fn print(line: &str) {
+    let _ = writeln!(stdout(), "{0}", line);
+}
+
+pub fn emit(event: &Event) {
+    if json() {
+        if let Ok(line) = serde_json::to_string(event) {
+            print(&line);
+        }
+    }
+}
+
+// Something a person following the sync should know.
+pub fn info(text: &str) {
+    if json() {
+        emit(&Event::Message { level: Level::Info, text });
+    } else {
+        print(text);
+    }
+}
+
+// Something that may need attention but doesn't stop the sync.
+pub fn warn(text: &str) {
+    if json() {
+        emit(&Event::Message { level: Level::Warning, text });
+    } else {
+        print(&format!("Warning: {0}", text));
+    }
+}
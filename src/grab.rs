@@ -1,12 +1,23 @@
-use crate::util::{sha256_sum, path_exists, LCError, uninstall};
+use crate::cfg;
+use crate::util::{check_bepinex, confirm, get_bytes, sha256_sum, path_exists, LCError};
+use crate::cache::{Cache, Label};
+use crate::download::{download_all, Download};
+use crate::events::{self, Event};
+use crate::manifest::{ClientRelease, Manifest, Pack};
+use crate::installed::{bepinex_db_path, bepinex_owned, db_path, defaults_dir, scan, InstallDb, InstalledBepInEx, InstalledFile, InstalledPlugin, SHARED_DIRS};
+use crate::sign::verify;
 use crate::steam::Steam;
+use crate::plan::{bepinex_action, merge, Action, BepInExAction, DowngradePolicy, Plan};
+use crate::resolve::{check, order, with_dependencies};
+use crate::selection::{Selection, Selections};
+use crate::transaction::Transaction;
+use crate::uninstall::uninstall;
 use anyhow::{Context, Result};
 use flate2::read::GzDecoder;
-use std::fs::{File, write, read_to_string, create_dir, remove_file, remove_dir_all};
-use reqwest::blocking::get;
+use std::fs::{File, write, read, read_dir, copy, create_dir, create_dir_all, metadata, remove_file};
 use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
 use tar::Archive;
-use fs_extra::dir::{CopyOptions, move_dir};
 
 pub type Plugins = Vec<Plugin>;
 
@@ -16,30 +27,103 @@ pub struct Plugin {
     pub sha256: String,
     pub version: String,
     pub tar_name: String,
+    #[serde(default)]
     pub files: Option<Vec<String>>,
+    #[serde(default)]
     pub folders: Option<Vec<String>>,
+    // Files (or folders of files) that are user-editable defaults rather than enforced by the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub defaults: Option<Vec<String>>,
+    // Plugins this one needs, by identifier, with the range of versions it works with, e.g. {"LethalLib": ">=0.10"}.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dependencies: Option<BTreeMap<String, String>>,
+    // Plugins this one can't be installed alongside, by identifier, with the range of versions it conflicts with ("*" for any).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflicts: Option<BTreeMap<String, String>>,
+    // Whether players can leave the plugin out, "required" (the default), "default-on" or "optional".
+    #[serde(default, skip_serializing_if = "Selection::is_required")]
+    pub selection: Selection,
+    // The server the plugin came from, recorded by the client when merging sources.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
     pub root: bool // This means the contents should be inside of BepInEx
 }
 
+// The plugins a plan removes and installs, in the order to do so: a plugin is installed after the plugins it depends on
+// and removed before them.
+fn ordered(actions: &[Action]) -> Result<(Vec<&Plugin>, Vec<&Plugin>)> {
+    let mut removals: Vec<&Plugin> = vec![];
+    let mut installs: Vec<&Plugin> = vec![];
+    for action in actions {
+        match action {
+            Action::Install { plugin } => installs.push(plugin),
+            Action::Upgrade { from, to } | Action::Downgrade { from, to } | Action::Changed { from, to } => {
+                removals.push(from);
+                installs.push(to);
+            }
+            Action::Reinstall { plugin } => {
+                removals.push(plugin);
+                installs.push(plugin);
+            }
+            Action::Remove { plugin } => removals.push(plugin),
+            Action::Unchanged { .. } => {}
+        }
+    }
+
+    let removal_ranks = order(&removals.iter().map(|plugin| (*plugin).clone()).collect::<Plugins>())?;
+    removals.sort_by_key(|plugin| std::cmp::Reverse(removal_ranks[&plugin.identifier.to_lowercase()]));
+    let install_ranks = order(&installs.iter().map(|plugin| (*plugin).clone()).collect::<Plugins>())?;
+    installs.sort_by_key(|plugin| install_ranks[&plugin.identifier.to_lowercase()]);
+    Ok((removals, installs))
+}
+
+impl Plugin {
+    // True if a path, relative to where the plugin is installed, is a user-editable default.
+    pub fn is_default(&self, path: &str) -> bool {
+        self.defaults.iter().flatten().any(|default| path == default || path.starts_with(&format!("{0}/", default.trim_end_matches('/'))))
+    }
+}
+
+// What the client's plugins.json says is installed.
+enum ClientState {
+    Fresh,
+    Broken,
+    Installed(Plugins)
+}
+
 pub struct Grab {
-    pub lc_download: String,
+    // Modpack servers, highest priority first.
+    pub sources: Vec<String>,
     pub lc_path: String,
     pub plugins: Vec<Plugin>,
+    // The highest priority source's modpack details.
+    pub pack: Pack,
     pub wipe: bool,
+    pub jobs: usize,
+    pub downgrade: DowngradePolicy,
+    // Answer yes to every question instead of asking.
+    pub assume_yes: bool,
+    pub cache: Cache,
+    pub trusted_keys: Vec<String>,
+    pub installed: InstallDb,
     pub steam: Steam
 }
 
 impl Grab {
-    // Returns true if the client side plugin's contents matches its manifest
+    // Returns true if the client side plugin's contents matches what was installed.
+    // Plugins installed before files were recorded are checked against their manifest instead.
     fn validate(&self, client_plugin: &Plugin) -> bool {
+        if let Some(record) = self.installed.get(&client_plugin.identifier) {
+            return record.intact(&format!("{0}/BepInEx", self.lc_path));
+        }
+
         if client_plugin.files.is_some() {
             for file in client_plugin.files.clone().unwrap() {
-                let plugin_file: String;
-                if client_plugin.root {
-                    plugin_file = format!("{0}/BepInEx/{1}", &self.lc_path, &file);
+                let plugin_file = if client_plugin.root {
+                    format!("{0}/BepInEx/{1}", &self.lc_path, &file)
                 } else {
-                    plugin_file = format!("{0}/BepInEx/plugins/{1}", &self.lc_path, &file);
-                }
+                    format!("{0}/BepInEx/plugins/{1}", &self.lc_path, &file)
+                };
                 if !path_exists(&plugin_file) {
                     return false;
                 }
@@ -48,12 +132,11 @@ impl Grab {
 
         if client_plugin.folders.is_some() {
             for folder in client_plugin.folders.clone().unwrap() {
-                let plugin_dir: String;
-                if client_plugin.root {
-                    plugin_dir = format!("{0}/BepInEx/{1}", &self.lc_path, &folder);
+                let plugin_dir = if client_plugin.root {
+                    format!("{0}/BepInEx/{1}", &self.lc_path, &folder)
                 } else {
-                    plugin_dir = format!("{0}/BepInEx/plugins/{1}", &self.lc_path, &folder);
-                }
+                    format!("{0}/BepInEx/plugins/{1}", &self.lc_path, &folder)
+                };
                 if !path_exists(&plugin_dir) {
                     return false;
                 }
@@ -63,190 +146,664 @@ impl Grab {
         true
     }
 
-    // If the files in the manifest exist, remove them.
-    fn remove_plugin(&self, client_plugin: &Plugin) -> Result<()> {
-        if client_plugin.root {
-            println!("{}", format!("{0} is a root plugin!", client_plugin.identifier));
-            return Ok(());
+    // The directory a plugin's archive is unpacked into.
+    fn install_dir(&self, plugin: &Plugin) -> String {
+        if plugin.root {
+            format!("{0}/BepInEx", self.lc_path)
+        } else {
+            format!("{0}/BepInEx/plugins", self.lc_path)
         }
-        // If the plugin still exists, have to delete it.
-        if client_plugin.files.is_some() {
-            for file in client_plugin.files.clone().unwrap() {
-                let plugin_file: String;
-                if client_plugin.root {
-                    plugin_file = format!("{0}/BepInEx/{1}", &self.lc_path, &file);
-                } else {
-                    plugin_file = format!("{0}/BepInEx/plugins/{1}", &self.lc_path, &file);
-                }
-                if path_exists(&plugin_file) {
-                    remove_file(&plugin_file).with_context(|| format!("Could not delete plugin: {0}", &file))?;
-                }
+    }
+
+    // True if removing `path` (relative to BepInEx/) would take it from BepInEx or another plugin, in which case it is reported and kept.
+    fn conflicts(&self, plugin: &Plugin, path: &str, installed: &InstallDb) -> bool {
+        let owner = if bepinex_owned(path) {
+            Some("BepInEx")
+        } else {
+            installed.owner(path, &plugin.identifier)
+        };
+        match owner {
+            Some(owner) => {
+                events::info(&format!("Conflict: keeping BepInEx/{0} from {1}, it also belongs to {2}", path, plugin.identifier, owner));
+                true
             }
+            None => false
         }
+    }
 
-        // If there are folders, search for them and delete them.
-        if client_plugin.folders.is_some() {
-            for folder in client_plugin.folders.clone().unwrap() {
-                let plugin_dir: String;
-                if client_plugin.root {
-                    plugin_dir = format!("{0}/BepInEx/{1}", &self.lc_path, &folder);
-                } else {
-                    plugin_dir = format!("{0}/BepInEx/plugins/{1}", &self.lc_path, &folder);
-                }
-                if path_exists(&plugin_dir) {
-                    remove_dir_all(&plugin_dir)
-                    .with_context(|| format!("Could not remove plugin folder: {0}", &folder))?;
+    // Move every file the plugin installed into the transaction's backup, along with any folders that are left empty.
+    // Paths that BepInEx or another plugin also own are left in place.
+    fn remove_plugin(&self, client_plugin: &Plugin, installed: &mut InstallDb, transaction: &mut Transaction) -> Result<()> {
+        let bepinex = format!("{0}/BepInEx", self.lc_path);
+        transaction.remove(&defaults_dir(&self.lc_path, &client_plugin.identifier))?;
+
+        if let Some(record) = installed.get(&client_plugin.identifier).cloned() {
+            installed.remove(&client_plugin.identifier);
+            for file in &record.files {
+                if self.conflicts(client_plugin, &file.path, installed) {
+                    continue;
                 }
+                transaction.remove(&format!("{0}/{1}", bepinex, file.path))
+                    .with_context(|| format!("Could not delete plugin: {0}", file.path))?;
             }
+            self.remove_empty_dirs(&record, transaction)?;
+            return Ok(());
+        }
+
+        // Installed before files were recorded, so all we know is what the manifest lists.
+        let prefix = if client_plugin.root { "" } else { "plugins/" };
+        for path in client_plugin.files.iter().flatten().chain(client_plugin.folders.iter().flatten()) {
+            let path = format!("{0}{1}", prefix, path);
+            if self.conflicts(client_plugin, &path, installed) {
+                continue;
+            }
+            transaction.remove(&format!("{0}/{1}", bepinex, path))
+                .with_context(|| format!("Could not delete plugin: {0}", path))?;
         }
 
         Ok(())
     }
 
-    // Download and validate server side.
-    fn create_plugin(&self, server_plugin: &Plugin) -> Result<()> {
-        // Download the corresponding plugin from the.
-        let resp = get(format!("{0}/{1}", &self.lc_download, server_plugin.tar_name)).context("Could not get file")?;
-        let body = resp.bytes().context("Could not convert file to bytes")?;
-        let plugin_tar_file = format!("./lc/{0}", server_plugin.tar_name);
-        write(&plugin_tar_file, body)
-            .context(format!("Could not write {0}", server_plugin.tar_name))?;
+    // Remove folders that held a plugin's files and are now empty, deepest first. BepInEx's own folders are kept.
+    fn remove_empty_dirs(&self, record: &InstalledPlugin, transaction: &mut Transaction) -> Result<()> {
+        let mut dirs: Vec<&str> = vec![];
+        for file in &record.files {
+            let mut path = file.path.as_str();
+            while let Some((parent, _)) = path.rsplit_once('/') {
+                if !SHARED_DIRS.contains(&parent) && !dirs.contains(&parent) {
+                    dirs.push(parent);
+                }
+                path = parent;
+            }
+        }
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.matches('/').count()));
 
-        // Process the sha256sum so as to validate integrity
-        let downloaded_plugin_tar_file_sha256 = sha256_sum(&format!("./lc/{0}", server_plugin.tar_name))
-                                                                            .with_context(|| format!("Could not compute checksum for {0}", server_plugin.tar_name))?;
-        if downloaded_plugin_tar_file_sha256 != server_plugin.sha256 {
-            return Err(LCError::CheckSumDiscrepency(format!("Could not write {0} because of sha256sum discrepency:\nServer: {1}\nDownload: {2}\n", 
-                server_plugin.tar_name,
-                server_plugin.sha256,
-                downloaded_plugin_tar_file_sha256
-            )).into());
+        for dir in dirs {
+            let dir = format!("{0}/BepInEx/{1}", self.lc_path, dir);
+            let empty = read_dir(&dir).map(|mut entries| entries.next().is_none()).unwrap_or(false);
+            if empty {
+                transaction.remove(&dir)?;
+            }
         }
 
+        Ok(())
+    }
+
+    // Unpack a cached and verified archive into the staging directory. Returns the staged directory and every file in it.
+    fn stage_plugin(&self, server_plugin: &Plugin, staging_dir: &str) -> Result<(String, Vec<InstalledFile>)> {
+        let plugin_tar_file = self.cache.path(&server_plugin.sha256);
+
         // Decompress and unpack the plugin archive.
+        let staged = format!("{0}/{1}", staging_dir, server_plugin.identifier);
+        create_dir(&staged).with_context(|| format!("Could not create staging directory for {0}", server_plugin.identifier))?;
         let dest: File = File::open(&plugin_tar_file)
             .with_context(|| format!("Could not open {0}", server_plugin.tar_name))?;
         let tar = GzDecoder::new(dest);
         let mut archive = Archive::new(tar);
         archive.set_overwrite(true);
-        if server_plugin.root {
-            archive.unpack(format!("{0}/BepInEx", self.lc_path))
-                .with_context(|| format!("Could not unpack {0}", server_plugin.tar_name))?;
-        } else {
-            archive.unpack(format!("{0}/BepInEx/plugins", self.lc_path))
-                .with_context(|| format!("Could not unpack {0}", server_plugin.tar_name))?;
+        archive.unpack(&staged)
+            .with_context(|| format!("Could not unpack {0}", server_plugin.tar_name))?;
+
+        // Record paths relative to BepInEx/, wherever the plugin is installed.
+        let prefix = if server_plugin.root { "" } else { "plugins/" };
+        let files = scan(&staged, prefix)?;
+
+        Ok((staged, files))
+    }
+
+    // Decide what each user-editable default is installed as: the server's default when the player hasn't changed it or doesn't have it,
+    // otherwise the player's copy, with BepInEx configs merged when the server's default changed too.
+    // The server's defaults are staged under `staged_defaults` as the base of the next merge.
+    fn resolve_defaults(&self, plugin: &Plugin, staged: &str, staged_defaults: &str, files: &mut [InstalledFile], transaction: &Transaction) -> Result<()> {
+        let prefix = if plugin.root { "" } else { "plugins/" };
+        let base_dir = defaults_dir(&self.lc_path, &plugin.identifier);
+
+        for file in files.iter_mut() {
+            let relative = file.path.strip_prefix(prefix).unwrap_or(&file.path).to_string();
+            if !plugin.is_default(&relative) {
+                continue;
+            }
+            file.default = true;
+
+            let new_path = format!("{0}/{1}", staged, relative);
+            let base_copy = format!("{0}/{1}", staged_defaults, relative);
+            if let Some(parent) = std::path::Path::new(&base_copy).parent() {
+                create_dir_all(parent).context("Could not stage default")?;
+            }
+            copy(&new_path, &base_copy).with_context(|| format!("Could not stage default {0}", file.path))?;
+
+            // The player's copy and the default it started from are either still in place or were just moved into the backup.
+            let find = |path: String| transaction.previous(&path).or(if path_exists(&path) { Some(path) } else { None });
+            let Some(current) = find(format!("{0}/BepInEx/{1}", self.lc_path, file.path)) else { continue };
+            let current_contents = read(&current).with_context(|| format!("Could not read {0}", current))?;
+            let new_contents = read(&new_path).with_context(|| format!("Could not read {0}", new_path))?;
+            let base_contents = match find(format!("{0}/{1}", base_dir, relative)) {
+                Some(base) => Some(read(&base).with_context(|| format!("Could not read {0}", base))?),
+                None => None
+            };
+
+            let merged = match base_contents {
+                // The player hasn't changed it, take the server's default.
+                Some(base) if base == current_contents => continue,
+                // Only the player changed it.
+                Some(base) if base == new_contents => current_contents,
+                Some(base) if relative.ends_with(".cfg") => {
+                    let (merged, conflicts) = cfg::merge(&String::from_utf8_lossy(&base), &String::from_utf8_lossy(&current_contents), &String::from_utf8_lossy(&new_contents));
+                    for conflict in conflicts {
+                        events::info(&format!("Keeping your {0} in BepInEx/{1}, the server's default changed too", conflict, file.path));
+                    }
+                    merged.into_bytes()
+                }
+                Some(_) => {
+                    events::info(&format!("Keeping your BepInEx/{0}, the server's default changed too", file.path));
+                    current_contents
+                }
+                None if current_contents == new_contents => continue,
+                // Installed before defaults were tracked, so the player's settings win but the server's new ones are still added.
+                None if relative.ends_with(".cfg") => {
+                    let new_text = String::from_utf8_lossy(&new_contents);
+                    let (merged, _) = cfg::merge(&new_text, &String::from_utf8_lossy(&current_contents), &new_text);
+                    events::info(&format!("Keeping your settings in BepInEx/{0} and adding the server's new ones, its earlier default wasn't recorded", file.path));
+                    merged.into_bytes()
+                }
+                None => {
+                    events::info(&format!("Keeping your BepInEx/{0}, its earlier default wasn't recorded so the server's changes can't be merged", file.path));
+                    current_contents
+                }
+            };
+            write(&new_path, merged).with_context(|| format!("Could not write {0}", new_path))?;
+            file.size = metadata(&new_path).context("Could not read merged default")?.len();
+            file.sha256 = sha256_sum(&new_path)?;
         }
 
-        // Delete the archive.
-        remove_file(&plugin_tar_file)
-            .with_context(|| format!("Could not remove {0}", server_plugin.tar_name))?;
+        Ok(())
+    }
+
+    // Switch a staged plugin into its install directory.
+    fn place_plugin(&self, server_plugin: &Plugin, staged: &str, transaction: &mut Transaction) -> Result<()> {
+        let install_dir = self.install_dir(server_plugin);
+        for entry in read_dir(staged).with_context(|| format!("Could not read staged {0}", server_plugin.identifier))? {
+            let name = entry.context("Could not read staged entry")?.file_name();
+            let name = name.to_string_lossy();
+            transaction.place(&format!("{0}/{1}", staged, name), &format!("{0}/{1}", install_dir, name))
+                .with_context(|| format!("Could not install {0}", server_plugin.identifier))?;
+        }
 
         Ok(())
     }
 
-    // Method used to sync the server's plugins with the client.
-    pub fn update(&mut self) -> Result<()> {
+    // Download a source's plugins.json and plugins.sha256 into `dir`, verify them and return the path of plugins.json.
+    fn download_manifest(&self, source: &str, dir: &str) -> Result<String> {
+        create_dir_all(dir).with_context(|| format!("Could not create {0}", dir))?;
+        let manifest = format!("{0}/plugins.json", dir);
+
         // Get the plugins.json from the link and store the body as bytes.
-        let mut resp = get(format!("{0}/plugins.json", &self.lc_download)).context("Could not get plugins.json")?;
-        let mut body = resp.bytes().context("Could not convert plugins.json to bytes")?;
-        write("./lc/plugins.json", body).context("Could not write plugins.json")?;
+        let body = get_bytes(&format!("{0}/plugins.json", source))?;
+        write(&manifest, body).context("Could not write plugins.json")?;
 
-        resp = get(format!("{0}/plugins.sha256", &self.lc_download)).context("Could not get plugins.sha256")?;
-        body = resp.bytes().context("Could not convert plugins.sha256 to bytes")?;
-        write("./lc/plugins.sha256", body).context("Could not write plugins.sha256")?;
+        let body = get_bytes(&format!("{0}/plugins.sha256", source))?;
 
         // Get the server plugins sha256 and compare it to the local, if they aren't a match that is a problem.
-        let plugins_sha256_file = read_to_string("./lc/plugins.sha256")
-                                            .context("Couldn't read plugins.sha256")?
-                                            .trim().to_string();
-        let download_plugins_sha256 = sha256_sum("./lc/plugins.json").context("Could not write ./lc/plugins.json because of sha256sum discrepency")?;
+        let plugins_sha256_file = String::from_utf8_lossy(&body).trim().to_string();
+        let download_plugins_sha256 = sha256_sum(&manifest)?;
 
         if plugins_sha256_file != download_plugins_sha256 {
-            panic!("Plugin sha256 do not match\nServer: {}\nDownload: {}", plugins_sha256_file, download_plugins_sha256);
+            return Err(LCError::CheckSumDiscrepency { name: format!("{0}/plugins.json", source), expected: plugins_sha256_file, actual: download_plugins_sha256 }.into());
         }
 
-        // Convert the latest server plugins.json to plugins object.
-        let server_plugins_str = read_to_string("./lc/plugins.json").context("Can't read server plugins.json to string")?;
-        let server_plugins_temp: Plugins = serde_json::from_str(&server_plugins_str).context("Could not serialize server plugins.json as Plugins")?;
-        self.plugins = server_plugins_temp;
+        // The checksum only guards against transfer errors, the signature proves the manifest came from the operator.
+        if !self.trusted_keys.is_empty() {
+            let body = get_bytes(&format!("{0}/plugins.sig", source)).context("Could not get plugins.sig, is the manifest signed?")?;
+            let signature = String::from_utf8_lossy(&body);
+            let contents = read(&manifest).context("Couldn't read plugins.json")?;
+            verify(&contents, &signature, &self.trusted_keys)?;
+        }
 
-        // If, for some reason, plugins doesn't exist, then create it.
-        if !path_exists(&format!("{0}/BepInEx/plugins", &self.lc_path)) {
-            create_dir(format!("{0}/BepInEx/plugins", &self.lc_path)).context("Could not create plugins")?
+        Ok(manifest)
+    }
+
+    // Download and verify a source's plugins.json and return its plugins.
+    fn fetch_source(&self, source: &str, dir: &str) -> Result<Manifest> {
+        Manifest::load(&self.download_manifest(source, dir)?)
+    }
+
+    // The latest lcmodmanager advertised by the highest priority source that advertises one. Only the release is read,
+    // so this works even when the rest of the manifest needs a newer client.
+    pub fn latest_client(&self) -> Result<Option<ClientRelease>> {
+        for (index, source) in self.sources.iter().enumerate() {
+            let manifest = self.download_manifest(source, &format!("./lc/sources/{0}", index))
+                .with_context(|| format!("Could not get plugins from {0}", source))?;
+            if let Some(release) = ClientRelease::load(&manifest)? {
+                return Ok(Some(release));
+            }
         }
+        Ok(None)
+    }
 
-        // If we are in wipe mode, delete then recreate.
-        else if self.wipe
-        {
-            remove_dir_all(format!("{0}/BepInEx/plugins", self.lc_path)).context("Could not remove plugins")?;
-            create_dir(format!("{0}/BepInEx/plugins", self.lc_path)).context("Could not create plugins in wipe")?
-        }
-
-        if path_exists(&format!("{0}/BepInEx/plugins/plugins.json", self.lc_path)) {
-            let client_plugins_str = read_to_string(format!("{0}/BepInEx/plugins/plugins.json", self.lc_path))
-                                                                .context("Can't read client plugins.json to string")?;
-            match serde_json::from_str(&client_plugins_str) {
-                Ok(plugins) => {
-                    let client_plugins: Plugins = plugins;
-
-                    let mut count: usize = 0;
-                    let client_plugins_size: usize = client_plugins.len().clone();
-
-                    // TODO: Need to handle root directory uninstalls (see HookGenPatcher), in the meantime, a reinstallation of bepinex will be adequate
-                    // Iterate through the server plugins.
-                    for plugin in &self.plugins {
-                        // If the plugins on the client are lexographically less they need to be removed (they don't match with the server.)
-                        while count < client_plugins_size && client_plugins[count].identifier.to_lowercase() < plugin.identifier.to_lowercase() {
-                            self.remove_plugin(&client_plugins[count])?;
-                            count += 1;
-                        }
-                        if count < client_plugins_size {
-                            // If the client and server match and their versions or sha256 aren't the same, update it.
-                            if client_plugins[count].identifier.to_lowercase() == plugin.identifier.to_lowercase() {
-                                if !self.validate(&client_plugins[count]) || client_plugins[count].version != plugin.version ||
-                                client_plugins[count].sha256 != plugin.sha256 {
-                                    self.remove_plugin(&client_plugins[count])?;
-                                    self.create_plugin(&plugin)?;
-                                }
-                                count += 1;
-                            }
-
-                            else if client_plugins[count].identifier.to_lowercase() > plugin.identifier.to_lowercase() {
-                                self.create_plugin(&plugin)?;
-                            }
-                        } else { self.create_plugin(&plugin)?; }
-                    }
+    // Fetch every source's plugins and merge them, a plugin from a higher priority source replaces one with the same identifier from a lower one.
+    // The merged plugins are written to ./lc/plugins.json with the source each one came from. Returns the sources that couldn't be reached.
+    fn fetch_manifest(&mut self) -> Result<Vec<String>> {
+        if self.trusted_keys.is_empty() {
+            events::warn("no trusted keys are configured, plugins.json signature was not checked");
+        }
 
-                    // If there is anything left over on the client side, delete it.
-                    while count < client_plugins_size {
-                        self.remove_plugin(&client_plugins[count])?;
-                        count += 1;
-                    }
+        let mut plugins: Plugins = vec![];
+        let mut pack: Option<Pack> = None;
+        let mut unavailable: Vec<String> = vec![];
+        for (index, source) in self.sources.iter().enumerate() {
+            // With several sources, one being down shouldn't remove every plugin it provides. A manifest that fails verification is still an error.
+            let source_manifest = match self.fetch_source(source, &format!("./lc/sources/{0}", index)) {
+                Ok(source_manifest) => source_manifest,
+                Err(err) if self.sources.len() > 1 && matches!(err.downcast_ref::<LCError>(), Some(LCError::Network { .. })) => {
+                    events::warn(&format!("could not reach {0} ({1}), keeping the plugins installed from it", source, err.root_cause()));
+                    unavailable.push(source.clone());
+                    continue;
                 }
-                // Broken install, clear, reinstall BepInEx, and write everything over to BepInEx.
-                Err(_) => {
-                    uninstall(&self.lc_path)?;
-                    self.steam.install_bepinex()?;
-                    for plugin in &self.plugins {
-                        self.create_plugin(plugin)?;
+                Err(err) => return Err(err.context(format!("Could not get plugins from {0}", source)))
+            };
+
+            // The modpack's BepInEx archive is hosted next to its plugins.json unless it says otherwise.
+            let mut source_pack = source_manifest.pack;
+            if let Some(release) = &mut source_pack.bepinex {
+                release.url.get_or_insert_with(|| format!("{0}/BepInEx.tar.gz", source));
+            }
+            pack.get_or_insert(source_pack);
+            for mut plugin in source_manifest.plugins {
+                if let Some(existing) = plugins.iter().find(|existing| existing.identifier.eq_ignore_ascii_case(&plugin.identifier)) {
+                    if existing.version != plugin.version {
+                        events::warn(&format!("{0} {1} from {2} is overridden by {3} from {4}",
+                                              plugin.identifier, plugin.version, source, existing.version, existing.source.as_deref().unwrap_or_default()));
                     }
+                    continue;
                 }
-            }            
-        // Fresh install, just write everything over to BepInEx
+                plugin.source = Some(source.clone());
+                plugins.push(plugin);
+            }
+        }
+        if unavailable.len() == self.sources.len() {
+            anyhow::bail!("None of the sources could be reached");
+        }
+
+        // The merge with the client's plugins walks both in identifier order.
+        plugins.sort_by_key(|plugin| plugin.identifier.to_lowercase());
+        // Dependencies and conflicts are checked once the player's selection is known, optional plugins may exclude each other.
+        order(&plugins)?;
+        self.plugins = plugins;
+        self.pack = pack.unwrap_or_default();
+        if let Some(name) = &self.pack.name {
+            events::info(&format!("Modpack: {0} {1}", name, self.pack.version.as_deref().unwrap_or_default()));
+        }
+
+        Ok(unavailable)
+    }
+
+    // Write the merged plugins to ./lc/plugins.json and plugins.sha256, these become the client's manifest after a sync.
+    fn write_manifest(&self) -> Result<()> {
+        Manifest::new(self.pack.clone(), self.plugins.clone()).write_to("./lc/plugins.json")?;
+        write("./lc/plugins.sha256", sha256_sum("./lc/plugins.json")?).context("Could not write plugins.sha256")
+    }
+
+    // Work out the plugins the client should end up with and write them as ./lc/plugins.json.
+    fn desired(&mut self, client_plugins: &Plugins, unavailable: &[String]) -> Result<()> {
+        self.keep_unavailable(client_plugins, unavailable);
+        self.select()?;
+        self.write_manifest()
+    }
+
+    // Plugins installed from a source that couldn't be reached are kept as they are, unless another source now provides them.
+    fn keep_unavailable(&mut self, client_plugins: &Plugins, unavailable: &[String]) {
+        if unavailable.is_empty() {
+            return;
+        }
+        for plugin in client_plugins {
+            // Plugins installed before sources were recorded came from the only source there was.
+            let source = plugin.source.as_deref().unwrap_or(&self.sources[0]);
+            let provided = self.plugins.iter().any(|other| other.identifier.eq_ignore_ascii_case(&plugin.identifier));
+            if unavailable.iter().any(|unavailable| unavailable == source) && !provided {
+                self.plugins.push(plugin.clone());
+            }
+        }
+        self.plugins.sort_by_key(|plugin| plugin.identifier.to_lowercase());
+    }
+
+    // Leave out the optional and default-on plugins the player doesn't want, unless a plugin they do want depends on them,
+    // then check the dependencies and conflicts of what is left.
+    fn select(&mut self) -> Result<()> {
+        let selections = Selections::load(&self.lc_path)?;
+        let mut wanted: Vec<bool> = self.plugins.iter().map(|plugin| selections.wants(plugin)).collect();
+        with_dependencies(&self.plugins, &mut wanted, |dependency, dependent| {
+            events::info(&format!("Installing {0} because {1} depends on it", dependency.identifier, dependent.identifier));
+        });
+
+        let mut wanted = wanted.into_iter();
+        self.plugins.retain(|_| wanted.next().unwrap_or(false));
+        check(&self.plugins)
+    }
+
+    // Opt in to or out of a plugin on the next sync. Required plugins can't be disabled.
+    pub fn set_enabled(&mut self, identifier: &str, enabled: bool) -> Result<()> {
+        self.fetch_manifest()?;
+        let Some(plugin) = self.plugins.iter().find(|plugin| plugin.identifier.eq_ignore_ascii_case(identifier)) else {
+            anyhow::bail!("The modpack has no plugin named {0}", identifier);
+        };
+        if plugin.selection.is_required() {
+            if enabled {
+                events::info(&format!("{0} is required, it is always installed", plugin.identifier));
+                return Ok(());
+            }
+            anyhow::bail!("{0} is required by the modpack and can't be disabled", plugin.identifier);
+        }
+
+        let mut selections = Selections::load(&self.lc_path)?;
+        selections.set(&plugin.identifier, enabled);
+        selections.save(&self.lc_path)?;
+        if enabled {
+            events::info(&format!("Enabled {0}, it will be installed on the next sync", plugin.identifier));
         } else {
-            remove_dir_all(format!("{0}/BepInEx/plugins", self.lc_path)).context("Could not remove plugins")?;
-            create_dir(format!("{0}/BepInEx/plugins", self.lc_path)).context("Could not create plugins in fresh/broken install")?;
-            for plugin in &self.plugins {
-                self.create_plugin(plugin)?;
+            events::info(&format!("Disabled {0}, it will be removed on the next sync", plugin.identifier));
+        }
+        Ok(())
+    }
+
+    // Print every plugin in the modpack and whether it will be installed, as a table or one plugin event each with JSON output.
+    pub fn list_plugins(&mut self) -> Result<()> {
+        self.fetch_manifest()?;
+        let selections = Selections::load(&self.lc_path)?;
+        let width = self.plugins.iter().map(|plugin| plugin.identifier.len()).max().unwrap_or(0).max("PLUGIN".len());
+        if !events::json() {
+            events::info(&format!("{0:<width$} {1:<12} {2:<10} ENABLED", "PLUGIN", "VERSION", "SELECTION", width = width));
+        }
+        for plugin in &self.plugins {
+            let selection = match plugin.selection {
+                Selection::Required => "required",
+                Selection::DefaultOn => "default-on",
+                Selection::Optional => "optional"
+            };
+            let enabled = selections.wants(plugin);
+            if events::json() {
+                events::emit(&Event::Plugin { identifier: &plugin.identifier, version: &plugin.version, selection, enabled });
+            } else {
+                events::info(&format!("{0:<width$} {1:<12} {2:<10} {3}", plugin.identifier, plugin.version, selection, if enabled { "yes" } else { "no" }, width = width));
+            }
+        }
+        Ok(())
+    }
+
+    // Where a plugin's archive is downloaded from.
+    fn archive_url(&self, plugin: &Plugin) -> String {
+        format!("{0}/{1}", plugin.source.as_deref().unwrap_or(&self.sources[0]), plugin.tar_name)
+    }
+
+    // Read the client's plugins.json to find out what is currently installed.
+    fn client_state(&self) -> Result<ClientState> {
+        let client_manifest = format!("{0}/BepInEx/plugins/plugins.json", self.lc_path);
+        if !path_exists(&client_manifest) {
+            return Ok(ClientState::Fresh);
+        }
+
+        // A manifest written by a newer lcmodmanager isn't broken, reinstalling everything would only lose what it installed.
+        match Manifest::load(&client_manifest) {
+            Ok(manifest) => Ok(ClientState::Installed(manifest.plugins)),
+            Err(err) if matches!(err.downcast_ref::<LCError>(), Some(LCError::UnsupportedManifest(_))) => Err(err.context("Could not read the installed plugins.json")),
+            Err(_) => Ok(ClientState::Broken)
+        }
+    }
+
+    // Work out what a sync would do without touching the game folder.
+    pub fn plan(&mut self) -> Result<Plan> {
+        let unavailable = self.fetch_manifest()?;
+        self.installed = InstallDb::load(&self.lc_path);
+
+        // Wiping, fresh and broken installs all start from an empty plugins folder, a broken one also reinstalls BepInEx.
+        // Wiping keeps root plugins, since they live outside the plugins folder.
+        let (reinstall, client_plugins) = match self.client_state()? {
+            ClientState::Installed(plugins) if !self.wipe => (false, plugins),
+            ClientState::Installed(plugins) => (false, plugins.into_iter().filter(|plugin| plugin.root).collect()),
+            ClientState::Broken => (true, vec![]),
+            _ => (false, vec![])
+        };
+        self.desired(&client_plugins, &unavailable)?;
+
+        Ok(Plan { bepinex: self.bepinex_action(reinstall), actions: merge(&client_plugins, &self.plugins, |plugin| self.validate(plugin)) })
+    }
+
+    // What BepInEx needs to match the modpack, installing it if it isn't there or `reinstall` is set.
+    fn bepinex_action(&self, reinstall: bool) -> Option<BepInExAction> {
+        let install = reinstall || !check_bepinex(&self.lc_path);
+        let mut missing = if install { vec![] } else { self.steam.missing_doorstop() };
+        // Without an archive there is nothing to restore them from.
+        if !missing.is_empty() && self.pack.bepinex.is_none() && self.steam.bepinex_download.is_empty() {
+            events::warn(&format!("BepInEx is missing {0} and BEPINEXDOWNLOAD isn't set to restore it from", missing.join(" and ")));
+            missing.clear();
+        }
+        bepinex_action(install, self.pack.bepinex.as_ref(), self.installed.bepinex.as_ref(), missing)
+    }
+
+    // The archive BepInEx is installed from: the modpack's build, cached like plugin archives, or BEPINEXDOWNLOAD for modpacks that don't name one.
+    fn bepinex_archive(&self) -> Result<String> {
+        let Some(release) = &self.pack.bepinex else { return self.steam.download_bepinex() };
+        if !self.cache.fetch(&release.sha256)? {
+            download_all(&[Download {
+                name: "BepInEx".to_string(),
+                url: release.url.clone().unwrap_or_else(|| format!("{0}/BepInEx.tar.gz", self.sources[0])),
+                dest: self.cache.path(&release.sha256),
+                sha256: release.sha256.clone()
+            }], 1)?;
+            self.cache.label(&release.sha256, Label { identifier: "BepInEx".to_string(), version: release.version.clone() })?;
+        }
+        Ok(self.cache.path(&release.sha256))
+    }
+
+    // Install, upgrade, downgrade or repair BepInEx and record the build that is installed.
+    // This happens outside the sync's transaction, so the record is written straight away.
+    fn apply_bepinex(&mut self, action: &BepInExAction) -> Result<()> {
+        let archive = self.bepinex_archive()?;
+        let files = match action {
+            BepInExAction::Install { .. } => self.steam.install_bepinex(&archive)?,
+            BepInExAction::Upgrade { to, .. } | BepInExAction::Downgrade { to, .. } => {
+                events::info(&format!("Replacing BepInEx with {0}, keeping plugins, configs and patchers", to));
+                self.steam.upgrade_bepinex(&archive)?
             }
+            BepInExAction::Repair { missing } => {
+                self.steam.repair_bepinex(&archive, missing)?;
+                events::info(&format!("Restored {0}", missing.join(" and ")));
+                // The legacy download isn't cached, unlike a release from the manifest.
+                if self.pack.bepinex.is_none() {
+                    remove_file(&archive).with_context(|| format!("Could not remove {0}", archive))?;
+                }
+                return Ok(());
+            }
+        };
+
+        self.installed.bepinex = Some(InstalledBepInEx {
+            version: self.pack.bepinex.as_ref().map(|release| release.version.clone()),
+            sha256: sha256_sum(&archive)?,
+            files
+        });
+        if self.pack.bepinex.is_none() {
+            remove_file(&archive).with_context(|| format!("Could not remove {0}", archive))?;
         }
+        create_dir_all(format!("{0}/.lcmodmanager", self.lc_path)).context("Could not create .lcmodmanager")?;
+        self.installed.save_bepinex(&self.lc_path)
+    }
 
-        // Move over plugins.json and plugins.sha256 and remove the directory.
-        let mut options = CopyOptions::new();
-        options.overwrite = true;
-        options.content_only = true;
-        move_dir("./lc/", format!("{0}/BepInEx/plugins", self.lc_path), &options).context("Could not move ./lc to plugins folder")?;
+    // Carry out each action of a plan in order. Every download is verified and staged before anything in the game folder changes.
+    fn apply(&self, actions: &[Action], wipe: bool, installed: &mut InstallDb, transaction: &mut Transaction) -> Result<()> {
+        let (removals, needed) = ordered(actions)?;
+
+        // Only download archives that aren't already in the cache.
+        let mut missing: Vec<&Plugin> = vec![];
+        for plugin in &needed {
+            if !self.cache.fetch(&plugin.sha256)? && !missing.iter().any(|other| other.sha256 == plugin.sha256) {
+                missing.push(plugin);
+            }
+        }
+
+        let downloads: Vec<Download> = missing.iter().map(|plugin| Download {
+            name: plugin.identifier.clone(),
+            url: self.archive_url(plugin),
+            dest: self.cache.path(&plugin.sha256),
+            sha256: plugin.sha256.clone()
+        }).collect();
+        download_all(&downloads, self.jobs)?;
+        for plugin in missing {
+            self.cache.label(&plugin.sha256, Label { identifier: plugin.identifier.clone(), version: plugin.version.clone() })?;
+        }
+
+        let staging_dir = transaction.staging_dir();
+        let mut staged: Vec<(String, Vec<InstalledFile>)> = vec![];
+        for plugin in &needed {
+            staged.push(self.stage_plugin(plugin, &staging_dir)?);
+        }
+
+        // Wiping, fresh and broken installs start from an empty plugins folder.
+        let plugins_dir = format!("{0}/BepInEx/plugins", self.lc_path);
+        if wipe {
+            transaction.remove(&plugins_dir)?;
+            transaction.create_dir(&plugins_dir)?;
+            installed.forget_plugins_dir();
+        }
+
+        // Remove everything being replaced or dropped first, so files moving between plugins aren't mistaken for conflicts.
+        for plugin in removals {
+            self.remove_plugin(plugin, installed, transaction)?;
+            events::emit(&Event::Removed { identifier: &plugin.identifier, version: &plugin.version });
+        }
+
+        for (plugin, (staged_dir, mut files)) in needed.into_iter().zip(staged) {
+            let staged_defaults = format!("{0}/.defaults/{1}", staging_dir, plugin.identifier.to_lowercase());
+            self.resolve_defaults(plugin, &staged_dir, &staged_defaults, &mut files, transaction)?;
+            for file in &files {
+                if let Some(owner) = installed.owner(&file.path, &plugin.identifier) {
+                    events::info(&format!("Conflict: {0} overwrites BepInEx/{1}, which {2} also installed", plugin.identifier, file.path, owner));
+                }
+            }
+            self.place_plugin(plugin, &staged_dir, transaction)?;
+            if path_exists(&staged_defaults) {
+                create_dir_all(format!("{0}/.lcmodmanager/defaults", self.lc_path)).context("Could not create defaults directory")?;
+                transaction.place(&staged_defaults, &defaults_dir(&self.lc_path, &plugin.identifier))?;
+            }
+            installed.insert(InstalledPlugin {
+                identifier: plugin.identifier.clone(),
+                version: plugin.version.clone(),
+                sha256: plugin.sha256.clone(),
+                root: plugin.root,
+                source: plugin.source.clone().unwrap_or_default(),
+                files
+            });
+            events::emit(&Event::Installed { identifier: &plugin.identifier, version: &plugin.version });
+        }
+
+        // Move over plugins.json, plugins.sha256 and the install database last, so they only change if everything else did.
+        transaction.place("./lc/plugins.json", &format!("{0}/plugins.json", plugins_dir))?;
+        transaction.place("./lc/plugins.sha256", &format!("{0}/plugins.sha256", plugins_dir))?;
+        // installed.json no longer holds BepInEx's record, installs recorded before bepinex.json existed move it there first.
+        if !path_exists(&bepinex_db_path(&self.lc_path)) {
+            installed.save_bepinex(&self.lc_path)?;
+        }
+        let staged_db = format!("{0}/installed.json", staging_dir);
+        installed.write_to(&staged_db)?;
+        transaction.place(&staged_db, &db_path(&self.lc_path))?;
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    // Apply the downgrade policy when the server rolls plugins back to older versions, before anything is downloaded.
+    fn confirm_downgrades(&self, plan: &Plan) -> Result<()> {
+        let bepinex = match &plan.bepinex {
+            Some(BepInExAction::Downgrade { from, to }) => Some(format!("  BepInEx {0} -> {1}", from, to)),
+            _ => None
+        };
+        let downgrades: Vec<String> = bepinex.into_iter().chain(plan.actions.iter().filter_map(|action| match action {
+            Action::Downgrade { from, to } => Some(format!("  {0} {1} -> {2}", to.identifier, from.version, to.version)),
+            _ => None
+        })).collect();
+        if downgrades.is_empty() {
+            return Ok(());
+        }
+
+        match self.downgrade {
+            DowngradePolicy::Allow => Ok(()),
+            DowngradePolicy::Refuse => Err(LCError::Cancelled(format!("The server downgrades plugins, refusing to sync (see --downgrade):\n{0}", downgrades.join("\n"))).into()),
+            DowngradePolicy::Ask => {
+                events::info(&format!("The server downgrades plugins:\n{0}", downgrades.join("\n")));
+                if self.assume_yes || confirm("Downgrade them?")? {
+                    Ok(())
+                } else {
+                    Err(LCError::Cancelled("Sync cancelled, nothing was changed".to_string()).into())
+                }
+            }
+        }
+    }
+
+    // Method used to sync the server's plugins with the client.
+    // If any step fails the previous plugins and manifest are restored.
+    pub fn update(&mut self) -> Result<()> {
+        let unavailable = self.fetch_manifest()?;
+
+        // Roll back anything left from an interrupted sync before reading the client manifest.
+        Transaction::recover(&self.lc_path)?;
+        self.installed = InstallDb::load(&self.lc_path);
+
+        let state = self.client_state()?;
+        let broken = matches!(state, ClientState::Broken);
+        let (wipe, client_plugins): (bool, Plugins) = match state {
+            ClientState::Installed(plugins) if !self.wipe => (false, plugins),
+            // Wiping only clears BepInEx/plugins, root plugins are still there and are synced like any other plugin.
+            ClientState::Installed(plugins) => (true, plugins.into_iter().filter(|plugin| plugin.root).collect()),
+            // Fresh, broken or wipe mode, just write everything over to BepInEx
+            _ => (true, vec![])
+        };
+        // The player's selection and its dependencies are settled before anything in the game folder changes.
+        self.desired(&client_plugins, &unavailable)?;
+
+        // Broken install, clear and reinstall BepInEx.
+        // This can't be rolled back, but anything lcmodmanager didn't install is backed up first.
+        // The player's configs stay in place and win over the archive's defaults.
+        let mut reinstalled: Option<BepInExAction> = None;
+        if broken {
+            uninstall(&self.lc_path, true)?;
+            self.installed = InstallDb::default();
+            let install = BepInExAction::Install { version: self.pack.bepinex.as_ref().map(|release| release.version.clone()) };
+            self.apply_bepinex(&install)?;
+            reinstalled = Some(install);
+        }
+
+        let plan = Plan {
+            bepinex: reinstalled.clone().or_else(|| self.bepinex_action(false)),
+            actions: merge(&client_plugins, &self.plugins, |plugin| self.validate(plugin))
+        };
+        events::emit(&Event::Plan(&plan));
+        self.confirm_downgrades(&plan)?;
+        if let (None, Some(action)) = (&reinstalled, &plan.bepinex) {
+            self.apply_bepinex(action)?;
+        }
+
+        // If, for some reason, plugins doesn't exist, then create it.
+        if !path_exists(&format!("{0}/BepInEx/plugins", &self.lc_path)) {
+            create_dir(format!("{0}/BepInEx/plugins", &self.lc_path)).context("Could not create plugins")?
+        }
+        let mut installed = std::mem::take(&mut self.installed);
+        let mut transaction = Transaction::begin(&self.lc_path)?;
+        let result = self.apply(&plan.actions, wipe, &mut installed, &mut transaction);
+        match result {
+            Ok(()) => {
+                self.installed = installed;
+                transaction.commit()?;
+                let count = |matches: fn(&Action) -> bool| plan.actions.iter().filter(|action| matches(action)).count();
+                events::emit(&Event::Summary {
+                    installed: count(|action| !matches!(action, Action::Remove { .. } | Action::Unchanged { .. })),
+                    removed: count(|action| matches!(action, Action::Remove { .. })),
+                    unchanged: count(|action| matches!(action, Action::Unchanged { .. }))
+                });
+                Ok(())
+            }
+            Err(err) => {
+                events::warn("Sync failed, restoring previous plugins...");
+                transaction.rollback().context("Could not restore previous plugins")?;
+                self.installed = InstallDb::load(&self.lc_path);
+                Err(err)
+            }
+        }
+    }
+}
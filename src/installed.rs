@@ -0,0 +1,160 @@
+use anyhow::{Context, Result};
+use crate::util::{path_exists, sha256_sum};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{metadata, read_dir, read_to_string, write};
+
+// Directories that BepInEx itself creates, these are never owned or removed by a plugin.
+pub const SHARED_DIRS: [&str; 5] = ["cache", "config", "core", "patchers", "plugins"];
+
+// Paths under BepInEx/ that belong to BepInEx, a root plugin never removes these even if its archive contained them.
+const BEPINEX_PATHS: [&str; 4] = ["cache", "core", "config/BepInEx.cfg", "LogOutput.log"];
+
+// True if one path is the same as, or inside, the other.
+pub fn overlaps(a: &str, b: &str) -> bool {
+    a == b || a.starts_with(&format!("{0}/", b)) || b.starts_with(&format!("{0}/", a))
+}
+
+// True if a path relative to BepInEx/ is one of BepInEx's directories or belongs to BepInEx itself.
+pub fn bepinex_owned(path: &str) -> bool {
+    SHARED_DIRS.contains(&path) || BEPINEX_PATHS.iter().any(|owned| overlaps(path, owned))
+}
+
+// A file lcmodmanager extracted, relative to BepInEx/.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InstalledFile {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    // A user-editable default, which the player is free to change.
+    #[serde(default)]
+    pub default: bool
+}
+
+// Everything a plugin's archive actually unpacked, regardless of what the manifest lists.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InstalledPlugin {
+    pub identifier: String,
+    pub version: String,
+    pub sha256: String,
+    pub root: bool,
+    // The server the plugin was installed from.
+    #[serde(default)]
+    pub source: String,
+    pub files: Vec<InstalledFile>
+}
+
+// The BepInEx build lcmodmanager installed. The version is only known when the modpack named one.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InstalledBepInEx {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub sha256: String,
+    // Every file in its archive, relative to the game folder.
+    pub files: Vec<String>
+}
+
+// The local record of which plugin owns which files, keyed by lowercase identifier, and of BepInEx itself.
+#[derive(Serialize, Deserialize, Default)]
+pub struct InstallDb {
+    pub plugins: BTreeMap<String, InstalledPlugin>,
+    // Saved to bepinex.json, installed.json only holds it for installs recorded before that existed.
+    #[serde(default, skip_serializing)]
+    pub bepinex: Option<InstalledBepInEx>
+}
+
+// Where the install database is kept, outside BepInEx/plugins so wiping plugins doesn't lose track of root plugins.
+pub fn db_path(lc_path: &str) -> String {
+    format!("{0}/.lcmodmanager/installed.json", lc_path)
+}
+
+// Where the BepInEx record is kept. BepInEx is shared by every profile, so it isn't swapped out with installed.json.
+pub fn bepinex_db_path(lc_path: &str) -> String {
+    format!("{0}/.lcmodmanager/bepinex.json", lc_path)
+}
+
+// Where the server's copy of a plugin's user-editable defaults is kept, the base of the next three-way merge.
+pub fn defaults_dir(lc_path: &str, identifier: &str) -> String {
+    format!("{0}/.lcmodmanager/defaults/{1}", lc_path, identifier.to_lowercase())
+}
+
+// Every file under `dir`, as paths relative to it prefixed with `prefix`, with their size and checksum.
+pub fn scan(dir: &str, prefix: &str) -> Result<Vec<InstalledFile>> {
+    let mut files: Vec<InstalledFile> = vec![];
+    for entry in read_dir(dir).with_context(|| format!("Could not read {0}", dir))? {
+        let entry = entry.with_context(|| format!("Could not read an entry of {0}", dir))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = format!("{0}/{1}", dir, name);
+        let relative = format!("{0}{1}", prefix, name);
+        let meta = metadata(&path).with_context(|| format!("Could not read {0}", path))?;
+        if meta.is_dir() {
+            files.extend(scan(&path, &format!("{0}/", relative))?);
+        } else {
+            files.push(InstalledFile { path: relative, size: meta.len(), sha256: sha256_sum(&path)?, default: false });
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+impl InstallDb {
+    // Load the install database, a missing or unreadable one is treated as empty.
+    pub fn load(lc_path: &str) -> InstallDb {
+        let mut db: InstallDb = read_to_string(db_path(lc_path)).ok()
+            .and_then(|db| serde_json::from_str(&db).ok())
+            .unwrap_or_default();
+        if let Some(bepinex) = read_to_string(bepinex_db_path(lc_path)).ok().and_then(|bepinex| serde_json::from_str(&bepinex).ok()) {
+            db.bepinex = Some(bepinex);
+        }
+        db
+    }
+
+    // Write the BepInEx record to bepinex.json, if there is one.
+    pub fn save_bepinex(&self, lc_path: &str) -> Result<()> {
+        let Some(bepinex) = &self.bepinex else { return Ok(()) };
+        let path = bepinex_db_path(lc_path);
+        let record = serde_json::to_string_pretty(bepinex).context("Could not serialize the BepInEx record")?;
+        write(&path, record).with_context(|| format!("Could not write {0}", path))
+    }
+
+    // Write the database to `path`, used to stage it so it is switched into place with the rest of a sync.
+    pub fn write_to(&self, path: &str) -> Result<()> {
+        let db = serde_json::to_string_pretty(self).context("Could not serialize install database")?;
+        write(path, db).context("Could not write install database")
+    }
+
+    pub fn get(&self, identifier: &str) -> Option<&InstalledPlugin> {
+        self.plugins.get(&identifier.to_lowercase())
+    }
+
+    pub fn insert(&mut self, plugin: InstalledPlugin) {
+        self.plugins.insert(plugin.identifier.to_lowercase(), plugin);
+    }
+
+    pub fn remove(&mut self, identifier: &str) {
+        self.plugins.remove(&identifier.to_lowercase());
+    }
+
+    // The other installed plugin that also owns a path, if there is one.
+    pub fn owner(&self, path: &str, except: &str) -> Option<&str> {
+        self.plugins.values()
+            .filter(|plugin| !plugin.identifier.eq_ignore_ascii_case(except))
+            .find(|plugin| plugin.files.iter().any(|file| overlaps(&file.path, path)))
+            .map(|plugin| plugin.identifier.as_str())
+    }
+
+    // Forget every plugin that only installed into BepInEx/plugins, after that folder was wiped.
+    pub fn forget_plugins_dir(&mut self) {
+        self.plugins.retain(|_, plugin| plugin.root);
+    }
+}
+
+impl InstalledPlugin {
+    // True if every recorded file is still present with the size it was installed with. User-editable defaults are the player's to change.
+    pub fn intact(&self, bepinex_path: &str) -> bool {
+        self.files.iter().filter(|file| !file.default).all(|file| {
+            let path = format!("{0}/{1}", bepinex_path, file.path);
+            path_exists(&path) && metadata(&path).map(|meta| meta.len() == file.size).unwrap_or(false)
+        })
+    }
+}
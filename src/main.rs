@@ -1,26 +1,70 @@
+mod cache;
+mod cfg;
+mod config;
+mod discover;
+mod download;
+mod events;
 mod grab;
+mod installed;
+mod manifest;
+mod plan;
+mod profile;
+mod publish;
+mod resolve;
+mod selection;
+mod self_update;
+mod serve;
+mod sign;
 mod steam;
+mod transaction;
+mod uninstall;
 mod util;
+mod vdf;
+mod version;
 
+use crate::cache::{Cache, parse_size};
+use crate::config::{CliOverrides, Config, Platform};
+use crate::discover::steam_roots;
+use crate::events::Event;
 use crate::grab::*;
+use crate::installed::InstallDb;
+use crate::plan::{DowngradePolicy, OutputFormat};
+use crate::manifest::Pack;
+use crate::profile::Profiles;
+use crate::self_update::{install, newer, offer, require_verified, SelfUpdatePolicy};
+use crate::publish::publish;
+use crate::serve::ModpackServer;
+use crate::sign::{generate_key, public_key, sign_file};
 use crate::steam::*;
-use crate::util::{path_exists, uninstall, check_bepinex};
-use std::io::{stdin, stdout, Read, Write};
+use crate::uninstall::uninstall;
+use crate::util::{exit_code, path_exists, purge, LCError};
+use std::io::{stderr, stdin, stdout, Read, Write};
 use std::fs::create_dir;
-use anyhow::{Result, Ok};
-use clap::Parser;
-use core::panic;
-use dotenvy_macro::dotenv;
+use std::path::Path;
+use std::process::ExitCode;
+use anyhow::{Context, Result, Ok};
+use clap::{Parser, Subcommand, ValueEnum};
+use indicatif::HumanBytes;
 
+// Keep the window open until the player has read the output, stdout only carries events with JSON output.
 fn exit() {
-    let mut stdout = stdout();
-    stdout.write(b"Success! Press enter to exit...").unwrap();
-    stdout.flush().unwrap();
-    stdin().read(&mut [0]).unwrap();
+    // Nothing is left to report to if the output was closed, so write errors are ignored.
+    if events::json() {
+        let _ = stderr().write_all(b"Success! Press enter to exit...");
+    } else {
+        let mut stdout = stdout();
+        let _ = stdout.write_all(b"Success! Press enter to exit...");
+        let _ = stdout.flush();
+    }
+    let _ = stdin().read(&mut [0]);
 }
 
 #[derive(Parser)]
+#[command(version)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long, default_value_t = false)]
     windows: bool,
 
@@ -36,126 +80,411 @@ struct Cli {
     #[arg(short, long, default_value_t = false)]
     uninstall: bool,
 
-    #[arg(long, default_value_t = ("").to_string())]
-    lethal_company_path: String,
+    /// Leave BepInEx/config in place when uninstalling
+    #[arg(long, requires = "uninstall", default_value_t = false)]
+    keep_config: bool,
+
+    /// Uninstall by deleting BepInEx entirely, without a backup
+    #[arg(long, requires = "uninstall", conflicts_with = "keep_config", default_value_t = false)]
+    purge: bool,
+
+    #[arg(long)]
+    lethal_company_path: Option<String>,
+
+    #[arg(long)]
+    steam_path: Option<String>,
+
+    /// Path to a config file, defaults to the platform config directory
+    #[arg(long, global = true)]
+    config: Option<String>,
+
+    #[arg(long)]
+    lc_download: Option<String>,
+
+    /// Modpack server to sync from, repeat for several with the highest priority first
+    #[arg(long = "source")]
+    sources: Vec<String>,
+
+    #[arg(long)]
+    bepinex_download: Option<String>,
+
+    #[arg(long)]
+    bepinex_sha256: Option<String>,
+
+    /// Comma separated base64 ed25519 public keys that plugins.json must be signed by
+    #[arg(long)]
+    trusted_keys: Option<String>,
+
+    /// How many plugins to download at once
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Directory plugin archives are cached in
+    #[arg(long, global = true)]
+    cache_dir: Option<String>,
+
+    /// How a fresh BepInEx install is finished: launch the game once, or only create BepInEx's folders
+    #[arg(long, value_enum)]
+    bepinex_bootstrap: Option<Bootstrap>,
+
+    /// Seconds to wait for the game's first launch to set up BepInEx
+    #[arg(long)]
+    bepinex_timeout: Option<u64>,
+
+    /// Print what a sync would do and exit without changing anything
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// What to do when the server downgrades a plugin
+    #[arg(long, value_enum)]
+    downgrade: Option<DowngradePolicy>,
+
+    /// Print for people, or write the plan, progress, results and errors as JSON lines
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+
+    /// Don't wait for Enter at the end and answer yes to every question
+    #[arg(short, long, alias = "non-interactive", default_value_t = false)]
+    yes: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect the resolved configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction
+    },
+    /// Manage the local cache of plugin archives
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction
+    },
+    /// Generate an ed25519 signing key for a modpack server and print its public key
+    Keygen {
+        /// Where to write the private key
+        #[arg(long, default_value_t = ("signing.key").to_string())]
+        out: String
+    },
+    /// Sign a plugins.json, writing plugins.sig next to it
+    Sign {
+        /// Private key created with keygen
+        #[arg(long)]
+        key: String,
+
+        #[arg(long, default_value_t = ("plugins.json").to_string())]
+        manifest: String
+    },
+    /// Build plugins.json and plugins.sha256 from a folder of plugin tar archives
+    Publish {
+        /// Folder containing the plugin archives, plugins.json is written here
+        #[arg(default_value_t = (".").to_string())]
+        dir: String,
+
+        /// Identifier of a new plugin that installs into BepInEx/ rather than BepInEx/plugins
+        #[arg(long)]
+        root: Vec<String>,
+
+        /// Also sign the manifest with this private key
+        #[arg(long)]
+        key: Option<String>
+    },
+    /// Manage named modpack profiles, each with its own server and plugins
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction
+    },
+    /// Choose which optional plugins to install
+    Plugin {
+        #[command(subcommand)]
+        action: PluginAction
+    },
+    /// Update lcmodmanager to the latest release the modpack offers
+    SelfUpdate {
+        /// Only report whether an update is available
+        #[arg(long)]
+        check: bool
+    },
+    /// Serve a modpack folder over HTTP so clients can sync from it
+    Serve {
+        /// Folder containing plugins.json and the plugin archives
+        #[arg(default_value_t = (".").to_string())]
+        dir: String,
+
+        /// Address to listen on
+        #[arg(long, default_value_t = ("0.0.0.0:8080").to_string())]
+        bind: String
+    }
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print each resolved value and where it came from
+    Show
+}
+
+#[derive(Subcommand)]
+enum ProfileAction {
+    /// Add a profile that syncs from a server, the first profile added keeps the plugins currently installed
+    Add {
+        name: String,
+
+        /// The profile's LCDOWNLOAD, the folder containing plugins.json
+        lc_download: String
+    },
+    /// List profiles, marking the active one
+    List,
+    /// Swap in another profile's plugins, the next sync uses its server
+    Switch {
+        name: String
+    },
+    /// Remove a profile that isn't active along with its stored plugins
+    Remove {
+        name: String
+    }
+}
+
+#[derive(Subcommand)]
+enum PluginAction {
+    /// List the modpack's plugins and whether each will be installed
+    List,
+    /// Install an optional plugin on the next sync
+    Enable {
+        identifier: String
+    },
+    /// Remove an optional or default-on plugin on the next sync, required plugins can't be disabled
+    Disable {
+        identifier: String
+    }
+}
+
+#[derive(Subcommand)]
+enum CacheAction {
+    /// List cached archives, most recently used first
+    List,
+    /// Recompute every checksum and remove corrupt archives
+    Verify,
+    /// Remove the least recently used archives until the cache fits
+    Prune {
+        /// Size to shrink the cache to, e.g. 500M or 2G (defaults to CACHELIMIT)
+        #[arg(long)]
+        max_size: Option<String>
+    }
+}
 
-    #[arg(long, default_value_t = ("").to_string())]
-    steam_path: String,
+// Errors are printed like anyhow's own, but each kind of failure exits with its own code.
+fn main() -> ExitCode {
+    if let Err(err) = run() {
+        let code = exit_code(&err);
+        events::emit(&Event::Error { message: &format!("{0:#}", err), code });
+        eprintln!("Error: {0:?}", err);
+        return ExitCode::from(code);
+    }
+    ExitCode::SUCCESS
 }
 
-fn main() -> Result<()> {
-    let mut cli = Cli::parse();
+fn run() -> Result<()> {
+    let cli = Cli::parse();
+    events::init(cli.output);
 
-    if !cli.steam_path.is_empty() && cli.flatpak {
-        panic!("Can't specify steam path with flatpak")
+    if cli.steam_path.is_some() && cli.flatpak {
+        return Err(LCError::Usage("Can't specify steam path with flatpak".to_string()).into());
     }
 
-    if cfg!(windows) && cli.flatpak || cli.linux {
-        panic!("Running Linux/Flatpak options on Windows")
+    if cfg!(windows) && (cli.flatpak || cli.linux) {
+        return Err(LCError::Usage("Running Linux/Flatpak options on Windows".to_string()).into());
     }
 
     if cfg!(unix) && cli.windows {
-        panic!("Running Windows options on Unix")
+        return Err(LCError::Usage("Running Windows options on Unix".to_string()).into());
     }
 
-    if cli.windows && cli.linux || cli.windows && cli.flatpak {
-        panic!("Windows and Linux options cannot be mixed")
+    if cli.windows && (cli.linux || cli.flatpak) {
+        return Err(LCError::Usage("Windows and Linux options cannot be mixed".to_string()).into());
     }
 
     if cli.flatpak && cli.linux {
-        panic!("Please specify either Linux or Windows")
+        return Err(LCError::Usage("Please specify either Linux or Windows".to_string()).into());
     }
 
     // In the event no specific system option is set, just go with system defaults
-    if !cli.windows && !cli.flatpak && !cli.linux {
-        if cfg!(windows) {
-            cli.windows = true;
-        } else {
-            cli.linux = true;
-        }
+    let platform = if cli.windows {
+        Platform::Windows
+    } else if cli.flatpak {
+        Platform::Flatpak
+    } else if cli.linux || !cfg!(windows) {
+        Platform::Linux
+    } else {
+        Platform::Windows
+    };
+
+    let overrides = CliOverrides {
+        lc_download: cli.lc_download,
+        sources: if cli.sources.is_empty() { None } else { Some(cli.sources.join(",")) },
+        bepinex_download: cli.bepinex_download,
+        bepinex_sha256: cli.bepinex_sha256,
+        trusted_keys: cli.trusted_keys,
+        lethal_company_path: cli.lethal_company_path,
+        steam_path: cli.steam_path,
+        jobs: cli.jobs,
+        cache_dir: cli.cache_dir,
+        downgrade: cli.downgrade.and_then(|policy| policy.to_possible_value()).map(|value| value.get_name().to_string()),
+        bepinex_bootstrap: cli.bepinex_bootstrap.and_then(|bootstrap| bootstrap.to_possible_value()).map(|value| value.get_name().to_string()),
+        bepinex_timeout: cli.bepinex_timeout
+    };
+    let mut config = Config::load(cli.config.as_deref(), platform, overrides)?;
+    // Only look through the Steam libraries, and maybe ask which install to use, for commands that work on the game.
+    // `config show` reports the settings as configured, it shouldn't stop to ask.
+    let needs_game = !matches!(cli.command, Some(Command::Cache { .. } | Command::Keygen { .. } | Command::Sign { .. }
+                                                 | Command::Publish { .. } | Command::Serve { .. } | Command::SelfUpdate { .. }
+                                                 | Command::Config { action: ConfigAction::Show }));
+    if needs_game {
+        config.discover_lc_path(platform, cli.yes)?;
+    }
+    let lc_path = config.lc_path(platform)?;
+    let mut profiles = Profiles::load(&lc_path)?;
+    if let Some((name, profile)) = profiles.active() {
+        config.apply_profile(name, &profile.lc_download);
     }
 
-    let lc_download = dotenv!("LCDOWNLOAD").to_string();
-    let bepinex_download = dotenv!("BEPINEXDOWNLOAD").to_string();
-    let bepinex_sha256 = dotenv!("BEPINEXSHA256").to_string();
+    // Commands that need the modpack's servers are handled once the grabber is set up.
+    let command = match cli.command {
+        Some(Command::Config { action: ConfigAction::Show }) => {
+            config.show();
+            return Ok(());
+        }
+        Some(Command::Cache { action }) => {
+            let cache = Cache::open(&config.cache_dir()?)?;
+            match action {
+                CacheAction::List => cache.list()?,
+                CacheAction::Verify => {
+                    let removed = cache.verify()?;
+                    println!("Removed {0} corrupt archives", removed);
+                }
+                CacheAction::Prune { max_size } => {
+                    let max_size = match max_size {
+                        Some(max_size) => parse_size(&max_size)?,
+                        None => config.cache_limit()?
+                    };
+                    let freed = cache.prune(max_size)?;
+                    println!("Freed {0}", HumanBytes(freed));
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Keygen { out }) => {
+            let public_key = generate_key(&out)?;
+            println!("Wrote private key to {0}, keep it secret.", out);
+            println!("Public key (add this to TRUSTEDKEYS or trusted_keys): {0}", public_key);
+            return Ok(());
+        }
+        Some(Command::Sign { key, manifest }) => {
+            let signature = Path::new(&manifest).with_file_name("plugins.sig").display().to_string();
+            sign_file(&manifest, &key, &signature)?;
+            println!("Signed {0} with {1}, wrote {2}", manifest, public_key(&key)?, signature);
+            return Ok(());
+        }
+        Some(Command::Publish { dir, root, key }) => {
+            publish(&dir, &root, key.as_deref())?;
+            return Ok(());
+        }
+        Some(Command::Profile { action }) => {
+            match action {
+                ProfileAction::Add { name, lc_download } => profiles.add(&name, &lc_download)?,
+                ProfileAction::List => profiles.list(),
+                ProfileAction::Switch { name } => profiles.switch(&name)?,
+                ProfileAction::Remove { name } => profiles.remove(&name)?
+            }
+            return Ok(());
+        }
+        Some(Command::Serve { dir, bind }) => {
+            ModpackServer::new(&dir)?.run(&bind)?;
+            return Ok(());
+        }
+        command => command
+    };
 
     // If lc doesn't exist, create it.
     if !path_exists("./lc") {
-        create_dir("./lc").expect("Could not create ./lc");
+        create_dir("./lc").context("Could not create ./lc")?;
     }
 
-    let steam: Steam;
-    let mut lc_path: String = cli.lethal_company_path;
+    let steam = Steam { lc_path: lc_path.clone(),
+                        run_command: config.steam_path(platform),
+                        bepinex_download: config.bepinex_download.value.clone(),
+                        bepinex_sha256: config.bepinex_sha256.value.clone(),
+                        flatpak: platform == Platform::Flatpak,
+                        proton_roots: if platform == Platform::Windows { vec![] } else { steam_roots(platform, &config.steam_path(platform))? },
+                        bootstrap: config.bepinex_bootstrap()?,
+                        timeout: config.bepinex_timeout()?
+                    };
 
-    if cli.windows {
-        // If the path is empty (e.g. not set by cli) then grab from default environmental variable
-        if lc_path.is_empty() {
-            lc_path = dotenv!("WINDOWSLCPATH").to_string();
-        }
+    let mut grabber = Grab { sources: config.sources(),
+                             lc_path: lc_path.clone(),
+                             plugins: vec![],
+                             pack: Pack::default(),
+                             wipe: cli.wipe,
+                             jobs: config.jobs()?,
+                             downgrade: config.downgrade()?,
+                             assume_yes: cli.yes,
+                             cache: Cache::open(&config.cache_dir()?)?,
+                             trusted_keys: config.trusted_keys(),
+                             installed: InstallDb::default(),
+                             steam
+                         };
 
-        let run_command;
-        if cli.steam_path.is_empty() {
-            run_command = dotenv!("WINDOWSTEAMPATH").to_string();
-        } else {
-            run_command = cli.steam_path;
-        }
-        steam = Steam { lc_path: lc_path.clone(), 
-                        run_command: run_command, 
-                        bepinex_download: bepinex_download,
-                        bepinex_sha256: bepinex_sha256,
-                        flatpak: cli.flatpak
-                    }
-    }
-    else if cli.linux {
-        if lc_path.is_empty() {
-            lc_path = dotenv!("LINUXLCPATH").to_string()
-                        .replace("~", &std::env::var("HOME").expect("Export your HOME, e.g. export HOME=/home/user"));
+    if let Some(Command::SelfUpdate { check }) = command {
+        let release = grabber.latest_client()?;
+        match release.as_ref().and_then(|release| newer(release).map(|binary| (release, binary))) {
+            None => events::info(&format!("lcmodmanager {0} is up to date", env!("CARGO_PKG_VERSION"))),
+            Some((release, _)) if check => events::info(&format!("lcmodmanager {0} is available (this is {1})", release.version, env!("CARGO_PKG_VERSION"))),
+            Some((release, binary)) => {
+                require_verified(!grabber.trusted_keys.is_empty())?;
+                install(release, binary)?
+            }
         }
+        return Ok(());
+    }
 
-        let run_command;
-        if cli.steam_path.is_empty() {
-            run_command = dotenv!("LINUXSTEAMPATH").to_string();
-        } else {
-            run_command = cli.steam_path;
-        }
-        steam = Steam { lc_path: lc_path.clone(), 
-                        run_command: run_command, 
-                        bepinex_download: bepinex_download,
-                        bepinex_sha256: bepinex_sha256,
-                        flatpak: cli.flatpak
-                    }
-    }
-    else {
-        if lc_path.is_empty() {
-            lc_path = dotenv!("FLATPAKLCPATH").to_string()
-                       .replace("~", &std::env::var("HOME").expect("Export your HOME, e.g. export HOME=/home/user"));
-        }
+    // Everything else works on the game folder.
+    if !path_exists(&lc_path) {
+        return Err(LCError::GameNotFound(lc_path).into());
+    }
 
-        let run_command;
-        if cli.steam_path.is_empty() {
-            run_command = dotenv!("FLATPAKPATH").to_string();
-        } else {
-            run_command = cli.steam_path;
+    if let Some(Command::Plugin { action }) = command {
+        match action {
+            PluginAction::List => grabber.list_plugins()?,
+            PluginAction::Enable { identifier } => grabber.set_enabled(&identifier, true)?,
+            PluginAction::Disable { identifier } => grabber.set_enabled(&identifier, false)?
         }
-        steam = Steam { lc_path: lc_path.clone(),
-                        run_command: run_command,
-                        bepinex_download: bepinex_download,
-                        bepinex_sha256: bepinex_sha256,
-                        flatpak: cli.flatpak
-                    }
+        return Ok(());
     }
 
-    if cli.uninstall {
-        uninstall(&lc_path)?;
-    } else {
-        // Check for BepInEx install at LC PATH, if it isn't there, install BepInEx to this machine
-        if !check_bepinex(&lc_path) { steam.install_bepinex()?; }
+    if cli.dry_run {
+        let plan = grabber.plan()?;
+        plan.print(cli.output);
+        return Ok(());
+    }
 
-        let mut grabber = Grab{ lc_download: lc_download, plugins: vec![], lc_path: lc_path, wipe: cli.wipe, steam: steam };
+    if cli.uninstall && cli.purge {
+        purge(&lc_path)?;
+    } else if cli.uninstall {
+        uninstall(&lc_path, cli.keep_config)?;
+    } else {
+        grabber.steam.check_launch_options(cli.yes)?;
+        // BepInEx is installed, upgraded or repaired as part of the sync.
         grabber.update()?;
+        if config.self_update()? == SelfUpdatePolicy::Ask {
+            offer(grabber.pack.client.as_ref(), !grabber.trusted_keys.is_empty(), cli.yes)?;
+        }
+
+        // Keep the cache within its limit, archives used by this sync were just touched so they are kept.
+        grabber.cache.prune(config.cache_limit()?)?;
     }
 
-    exit();
+    if !cli.yes {
+        exit();
+    }
 
     Ok(())
 }
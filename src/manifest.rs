@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use crate::grab::Plugins;
+use crate::util::LCError;
+use crate::version::compare;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, write};
+
+// The newest plugins.json schema this client understands. Version 0 is the original bare array of plugins.
+pub const SCHEMA_VERSION: u64 = 1;
+
+// Details about the modpack as a whole.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Pack {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    // The oldest lcmodmanager that can install the modpack.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_client_version: Option<String>,
+    // The latest lcmodmanager, offered to clients running an older one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<ClientRelease>,
+    // The BepInEx build the modpack runs on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bepinex: Option<BepInExRelease>
+}
+
+// A BepInEx archive, downloaded from BepInEx.tar.gz next to plugins.json unless it has its own url.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BepInExRelease {
+    pub version: String,
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>
+}
+
+// A build of lcmodmanager for one platform.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Binary {
+    pub url: String,
+    pub sha256: String
+}
+
+// A lcmodmanager release, with a build for each platform keyed like "linux-x86_64" or "windows-x86_64".
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClientRelease {
+    pub version: String,
+    pub binaries: BTreeMap<String, Binary>
+}
+
+impl ClientRelease {
+    // Read only the client release from a plugins.json, so clients too old for the rest of the manifest can still update.
+    pub fn load(path: &str) -> Result<Option<ClientRelease>> {
+        let manifest = read_to_string(path).with_context(|| format!("Could not read {0}", path))?;
+        let value: Value = serde_json::from_str(&manifest).map_err(|err| LCError::ManifestParse(err.to_string()))?;
+        match value.get("client") {
+            Some(client) => Ok(Some(serde_json::from_value(client.clone()).map_err(|err| LCError::ManifestParse(format!("client: {0}", err)))?)),
+            None => Ok(None)
+        }
+    }
+}
+
+// plugins.json as of the current schema.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    pub schema_version: u64,
+    #[serde(flatten)]
+    pub pack: Pack,
+    pub plugins: Plugins
+}
+
+// Every schema version this client can read, each migrates to the next one up.
+enum Schema {
+    V0(Plugins),
+    V1(Manifest)
+}
+
+impl Schema {
+    fn migrate(self) -> Schema {
+        match self {
+            Schema::V0(plugins) => Schema::V1(Manifest { schema_version: 1, pack: Pack::default(), plugins }),
+            latest => latest
+        }
+    }
+
+    fn latest(mut self) -> Manifest {
+        loop {
+            match self {
+                Schema::V1(manifest) => return manifest,
+                older => self = older.migrate()
+            }
+        }
+    }
+}
+
+impl Manifest {
+    pub fn new(pack: Pack, plugins: Plugins) -> Manifest {
+        Manifest { schema_version: SCHEMA_VERSION, pack, plugins }
+    }
+
+    // Parse any schema version up to ours and migrate it to the current one. Newer schemas, and modpacks that need a newer client,
+    // are refused with LCError::UnsupportedManifest rather than being misread.
+    pub fn parse(manifest: &str) -> Result<Manifest> {
+        let invalid = |err: serde_json::Error| LCError::ManifestParse(err.to_string());
+        let value: Value = serde_json::from_str(manifest).map_err(invalid)?;
+        let schema = if value.is_array() {
+            Schema::V0(serde_json::from_value(value).map_err(invalid)?)
+        } else {
+            let schema_version = value.get("schema_version").and_then(Value::as_u64)
+                .ok_or_else(|| LCError::ManifestParse("schema_version is missing or not a whole number".to_string()))?;
+            match schema_version {
+                // Schema 0 is the bare list of plugins, an object never has it.
+                0 => return Err(LCError::ManifestParse("schema_version 0 is only used by a bare list of plugins".to_string()).into()),
+                1 => Schema::V1(serde_json::from_value(value).map_err(invalid)?),
+                newer => return Err(LCError::UnsupportedManifest(
+                    format!("plugins.json uses schema version {0} but this lcmodmanager only supports up to {1}", newer, SCHEMA_VERSION)).into())
+            }
+        };
+
+        let manifest = schema.latest();
+        if let Some(min_client_version) = &manifest.pack.min_client_version {
+            let client_version = env!("CARGO_PKG_VERSION");
+            if compare(client_version, min_client_version) == Some(Ordering::Less) {
+                return Err(LCError::UnsupportedManifest(
+                    format!("the modpack needs lcmodmanager {0} or newer, this is {1}", min_client_version, client_version)).into());
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn load(path: &str) -> Result<Manifest> {
+        let manifest = read_to_string(path).with_context(|| format!("Could not read {0}", path))?;
+        Manifest::parse(&manifest)
+    }
+
+    pub fn write_to(&self, path: &str) -> Result<()> {
+        let manifest = serde_json::to_string_pretty(self).context("Could not serialize plugins.json")?;
+        write(path, manifest).with_context(|| format!("Could not write {0}", path))
+    }
+}
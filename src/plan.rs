@@ -0,0 +1,253 @@
+use crate::events::{self, Event};
+use crate::grab::{Plugin, Plugins};
+use crate::installed::InstalledBepInEx;
+use crate::manifest::BepInExRelease;
+use crate::version::compare;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::cmp::Ordering;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json
+}
+
+// What to do when the server rolls a plugin back to an older version.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum DowngradePolicy {
+    Allow,
+    Ask,
+    Refuse
+}
+
+// A single step of a sync. Plans list them by identifier, they are applied in dependency order.
+// Plans are small, so the plugins aren't boxed.
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Clone)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    // On the server but not the client.
+    Install { plugin: Plugin },
+    // On both, and the server has a newer version. Versions that can't be compared count as upgrades.
+    Upgrade { from: Plugin, to: Plugin },
+    // On both, and the server has an older version.
+    Downgrade { from: Plugin, to: Plugin },
+    // On both with the same version, but the archive changed.
+    Changed { from: Plugin, to: Plugin },
+    // On both and unchanged, but the installed files failed validation.
+    Reinstall { plugin: Plugin },
+    // On the client but no longer on the server.
+    Remove { plugin: Plugin },
+    // On both and nothing needs to happen.
+    Unchanged { plugin: Plugin }
+}
+
+impl Action {
+    pub fn identifier(&self) -> &str {
+        match self {
+            Action::Install { plugin } | Action::Reinstall { plugin } |
+            Action::Remove { plugin } | Action::Unchanged { plugin } => &plugin.identifier,
+            Action::Upgrade { to, .. } | Action::Downgrade { to, .. } | Action::Changed { to, .. } => &to.identifier
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Install { .. } => "install",
+            Action::Upgrade { .. } => "upgrade",
+            Action::Downgrade { .. } => "downgrade",
+            Action::Changed { .. } => "changed",
+            Action::Reinstall { .. } => "reinstall",
+            Action::Remove { .. } => "remove",
+            Action::Unchanged { .. } => "unchanged"
+        }
+    }
+
+    // The (from, to) versions shown in the plan table.
+    fn versions(&self) -> (&str, &str) {
+        match self {
+            Action::Install { plugin } => ("", &plugin.version),
+            Action::Upgrade { from, to } | Action::Downgrade { from, to } | Action::Changed { from, to } => (&from.version, &to.version),
+            Action::Reinstall { plugin } | Action::Unchanged { plugin } => (&plugin.version, &plugin.version),
+            Action::Remove { plugin } => (&plugin.version, "")
+        }
+    }
+}
+
+// What a sync does to BepInEx itself, before any plugin.
+#[derive(Serialize, Clone)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum BepInExAction {
+    // Not installed, or the install is broken. The version is the modpack's, if it names one.
+    Install { version: Option<String> },
+    // The modpack has a newer build, or one lcmodmanager didn't record installing.
+    Upgrade { from: Option<String>, to: String },
+    // The modpack has an older build.
+    Downgrade { from: String, to: String },
+    // Current, but files that load it into the game are missing.
+    Repair { missing: Vec<String> }
+}
+
+#[derive(Serialize)]
+pub struct Plan {
+    pub bepinex: Option<BepInExAction>,
+    pub actions: Vec<Action>
+}
+
+// Work out what BepInEx needs from whether it has to be installed, the modpack's build, the recorded install and the missing doorstop files.
+pub fn bepinex_action(install: bool, release: Option<&BepInExRelease>, installed: Option<&InstalledBepInEx>, missing: Vec<String>) -> Option<BepInExAction> {
+    if install {
+        return Some(BepInExAction::Install { version: release.map(|release| release.version.clone()) });
+    }
+    if let Some(release) = release {
+        if installed.map(|installed| installed.sha256.as_str()) != Some(release.sha256.as_str()) {
+            let from = installed.and_then(|installed| installed.version.clone());
+            return Some(match &from {
+                Some(from) if compare(from, &release.version) == Some(Ordering::Greater) => BepInExAction::Downgrade { from: from.clone(), to: release.version.clone() },
+                _ => BepInExAction::Upgrade { from, to: release.version.clone() }
+            });
+        }
+    }
+    if missing.is_empty() {
+        None
+    } else {
+        Some(BepInExAction::Repair { missing })
+    }
+}
+
+// Merge the client and server manifests into the list of actions needed to sync them.
+// Both lists must be sorted by identifier (case insensitive), `valid` reports whether an installed plugin's files are intact.
+pub fn merge<F: Fn(&Plugin) -> bool>(client_plugins: &Plugins, server_plugins: &Plugins, valid: F) -> Vec<Action> {
+    let mut actions: Vec<Action> = vec![];
+    let mut count: usize = 0;
+    let client_plugins_size: usize = client_plugins.len();
+
+    // Iterate through the server plugins.
+    for plugin in server_plugins {
+        // If the plugins on the client are lexographically less they need to be removed (they don't match with the server.)
+        while count < client_plugins_size && client_plugins[count].identifier.to_lowercase() < plugin.identifier.to_lowercase() {
+            actions.push(Action::Remove { plugin: client_plugins[count].clone() });
+            count += 1;
+        }
+
+        if count < client_plugins_size && client_plugins[count].identifier.to_lowercase() == plugin.identifier.to_lowercase() {
+            let client_plugin = &client_plugins[count];
+            // If the client and server match and their versions or sha256 aren't the same, update it.
+            if client_plugin.version != plugin.version || client_plugin.sha256 != plugin.sha256 {
+                let (from, to) = (client_plugin.clone(), plugin.clone());
+                actions.push(match compare(&client_plugin.version, &plugin.version) {
+                    Some(Ordering::Greater) => Action::Downgrade { from, to },
+                    Some(Ordering::Equal) => Action::Changed { from, to },
+                    Some(Ordering::Less) | None => Action::Upgrade { from, to }
+                });
+            } else if !valid(client_plugin) {
+                actions.push(Action::Reinstall { plugin: plugin.clone() });
+            } else {
+                actions.push(Action::Unchanged { plugin: plugin.clone() });
+            }
+            count += 1;
+        } else {
+            actions.push(Action::Install { plugin: plugin.clone() });
+        }
+    }
+
+    // If there is anything left over on the client side, delete it.
+    while count < client_plugins_size {
+        actions.push(Action::Remove { plugin: client_plugins[count].clone() });
+        count += 1;
+    }
+
+    actions
+}
+
+impl Plan {
+    // True if applying the plan would change anything.
+    pub fn has_changes(&self) -> bool {
+        self.bepinex.is_some() || self.actions.iter().any(|action| !matches!(action, Action::Unchanged { .. }))
+    }
+
+    pub fn print(&self, format: OutputFormat) {
+        match format {
+            OutputFormat::Table => self.print_table(),
+            OutputFormat::Json => events::emit(&Event::Plan(self))
+        }
+    }
+
+    fn print_table(&self) {
+        match &self.bepinex {
+            Some(BepInExAction::Install { version: Some(version) }) => println!("BepInEx is not installed and {0} will be installed first.", version),
+            Some(BepInExAction::Install { version: None }) => println!("BepInEx is not installed and will be installed first."),
+            Some(BepInExAction::Upgrade { from, to }) => println!("BepInEx will be upgraded from {0} to {1} first.", from.as_deref().unwrap_or("an unrecorded version"), to),
+            Some(BepInExAction::Downgrade { from, to }) => println!("BepInEx will be downgraded from {0} to {1} first.", from, to),
+            Some(BepInExAction::Repair { missing }) => println!("BepInEx is missing {0} and will be repaired first.", missing.join(" and ")),
+            None => {}
+        }
+
+        let width = self.actions.iter().map(|action| action.identifier().len()).max().unwrap_or(0).max("PLUGIN".len());
+        print_row(["ACTION", "PLUGIN", "FROM", "TO"], width);
+        for action in &self.actions {
+            let (from, to) = action.versions();
+            print_row([action.name(), action.identifier(), from, to], width);
+        }
+
+        if !self.has_changes() {
+            println!("Everything is up to date.");
+        }
+    }
+}
+
+fn print_row(columns: [&str; 4], width: usize) {
+    println!("{0:<10} {1:<width$} {2:<12} {3}", columns[0], columns[1], columns[2], columns[3], width = width);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(identifier: &str, version: &str, sha256: &str) -> Plugin {
+        serde_json::from_value(serde_json::json!({
+            "identifier": identifier, "sha256": sha256, "version": version, "tar_name": format!("{0}.tar.gz", identifier), "root": false
+        })).unwrap()
+    }
+
+    fn summary(actions: &[Action]) -> Vec<(&'static str, String)> {
+        actions.iter().map(|action| (action.name(), action.identifier().to_string())).collect()
+    }
+
+    #[test]
+    fn classifies_every_action() {
+        let client = vec![
+            plugin("Broken", "1.0.0", "a"),
+            plugin("Changed", "1.0.0", "a"),
+            plugin("Down", "2.0.0", "a"),
+            plugin("gone", "1.0.0", "a"),
+            plugin("Same", "1.0.0", "a"),
+            plugin("Up", "1.0.0", "a")
+        ];
+        let server = vec![
+            plugin("Broken", "1.0.0", "a"),
+            plugin("Changed", "1.0.0", "b"),
+            plugin("Down", "1.0.0", "b"),
+            plugin("New", "1.0.0", "a"),
+            plugin("same", "1.0.0", "a"),
+            plugin("Up", "1.1.0", "b")
+        ];
+        let actions = merge(&client, &server, |plugin| plugin.identifier != "Broken");
+        let expected = [("reinstall", "Broken"), ("changed", "Changed"), ("downgrade", "Down"), ("remove", "gone"),
+                        ("install", "New"), ("unchanged", "same"), ("upgrade", "Up")];
+        assert_eq!(summary(&actions), expected.iter().map(|(name, identifier)| (*name, identifier.to_string())).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn versions_that_cant_be_compared_upgrade() {
+        let actions = merge(&vec![plugin("A", "beta", "a")], &vec![plugin("A", "nightly", "b")], |_| true);
+        assert_eq!(summary(&actions), vec![("upgrade", "A".to_string())]);
+    }
+
+    #[test]
+    fn empty_sides() {
+        assert_eq!(summary(&merge(&vec![], &vec![plugin("A", "1", "a")], |_| true)), vec![("install", "A".to_string())]);
+        assert_eq!(summary(&merge(&vec![plugin("A", "1", "a")], &vec![], |_| true)), vec![("remove", "A".to_string())]);
+    }
+}
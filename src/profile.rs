@@ -0,0 +1,207 @@
+use anyhow::{Context, Result};
+use crate::installed::{bepinex_owned, db_path, scan, InstallDb};
+use crate::selection::selections_path;
+use crate::transaction::Transaction;
+use crate::util::path_exists;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read_to_string, remove_dir_all, write};
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Profile {
+    pub lc_download: String
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct State {
+    active: Option<String>,
+    profiles: BTreeMap<String, Profile>
+}
+
+// Named modpacks for one game folder. The active profile's plugins are installed, every other profile's
+// plugins, install database, defaults and plugin selection are kept under .lcmodmanager/profiles/<name> until it is switched to.
+pub struct Profiles {
+    lc_path: String,
+    state: State
+}
+
+fn profiles_path(lc_path: &str) -> String {
+    format!("{0}/.lcmodmanager/profiles.json", lc_path)
+}
+
+// Where an inactive profile's plugins are kept.
+fn slot_dir(lc_path: &str, name: &str) -> String {
+    format!("{0}/.lcmodmanager/profiles/{1}", lc_path, name)
+}
+
+fn create_parent(path: &str) -> Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        create_dir_all(parent).with_context(|| format!("Could not create {0}", parent.display()))?;
+    }
+    Ok(())
+}
+
+fn write_state(state: &State, path: &str) -> Result<()> {
+    let state = serde_json::to_string_pretty(state).context("Could not serialize profiles.json")?;
+    write(path, state).context("Could not write profiles.json")
+}
+
+impl Profiles {
+    pub fn load(lc_path: &str) -> Result<Profiles> {
+        let path = profiles_path(lc_path);
+        let state = if path_exists(&path) {
+            let state_str = read_to_string(&path).context("Could not read profiles.json")?;
+            serde_json::from_str(&state_str).context("Could not parse profiles.json")?
+        } else {
+            State::default()
+        };
+        Ok(Profiles { lc_path: lc_path.to_string(), state })
+    }
+
+    fn save(&self) -> Result<()> {
+        create_dir_all(format!("{0}/.lcmodmanager", self.lc_path)).context("Could not create .lcmodmanager")?;
+        write_state(&self.state, &profiles_path(&self.lc_path))
+    }
+
+    // The profile whose plugins are installed, if any profiles have been added.
+    pub fn active(&self) -> Option<(&str, &Profile)> {
+        let name = self.state.active.as_deref()?;
+        self.state.profiles.get(name).map(|profile| (name, profile))
+    }
+
+    pub fn add(&mut self, name: &str, lc_download: &str) -> Result<()> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            anyhow::bail!("Profile names may only contain letters, numbers, - and _: {0}", name);
+        }
+        if self.state.profiles.contains_key(name) {
+            anyhow::bail!("Profile {0} already exists", name);
+        }
+
+        self.state.profiles.insert(name.to_string(), Profile { lc_download: lc_download.to_string() });
+        if self.state.active.is_none() {
+            self.state.active = Some(name.to_string());
+            println!("Added {0}, it is active and keeps the plugins that are currently installed", name);
+        } else {
+            println!("Added {0}, switch to it with `lcmodmanager profile switch {0}`", name);
+        }
+        self.save()
+    }
+
+    pub fn list(&self) {
+        if self.state.profiles.is_empty() {
+            println!("No profiles, add one with `lcmodmanager profile add <name> <url>`");
+        }
+        for (name, profile) in &self.state.profiles {
+            let marker = if self.state.active.as_deref() == Some(name) { "*" } else { " " };
+            println!("{0} {1:<16} {2}", marker, name, profile.lc_download);
+        }
+    }
+
+    // Forget an inactive profile and delete its stored plugins.
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        if !self.state.profiles.contains_key(name) {
+            anyhow::bail!("No profile named {0}", name);
+        }
+        if self.state.active.as_deref() == Some(name) {
+            anyhow::bail!("{0} is the active profile, switch to another profile before removing it", name);
+        }
+
+        let slot = slot_dir(&self.lc_path, name);
+        if path_exists(&slot) {
+            remove_dir_all(&slot).with_context(|| format!("Could not remove {0}", slot))?;
+        }
+        self.state.profiles.remove(name);
+        println!("Removed {0}", name);
+        self.save()
+    }
+
+    // Swap the active profile's plugins out and another profile's in. Everything is moved within the game folder,
+    // so nothing is downloaded, and a failure part way moves everything back.
+    pub fn switch(&mut self, name: &str) -> Result<()> {
+        if !self.state.profiles.contains_key(name) {
+            anyhow::bail!("No profile named {0}", name);
+        }
+        let Some(active) = self.state.active.clone() else { anyhow::bail!("No profile is active") };
+        if active == name {
+            println!("{0} is already the active profile", name);
+            return Ok(());
+        }
+
+        let mut transaction = Transaction::begin(&self.lc_path)?;
+        match self.swap(&active, name, &mut transaction) {
+            Ok(()) => transaction.commit()?,
+            Err(err) => {
+                println!("Switching profiles failed, restoring {0}...", active);
+                transaction.rollback().context("Could not restore the previous profile")?;
+                return Err(err);
+            }
+        }
+        self.state.active = Some(name.to_string());
+
+        // Only the empty folders the restored files were kept in are left.
+        let slot = slot_dir(&self.lc_path, name);
+        if path_exists(&slot) {
+            remove_dir_all(&slot).with_context(|| format!("Could not remove {0}", slot))?;
+        }
+
+        println!("Switched to {0}, run lcmodmanager to sync it with {1}", name, self.state.profiles[name].lc_download);
+        Ok(())
+    }
+
+    fn swap(&self, from: &str, to: &str, transaction: &mut Transaction) -> Result<()> {
+        let bepinex = format!("{0}/BepInEx", self.lc_path);
+        let defaults = format!("{0}/.lcmodmanager/defaults", self.lc_path);
+
+        // Keep the active profile's plugins, including files root plugins installed outside BepInEx/plugins.
+        let from_slot = slot_dir(&self.lc_path, from);
+        if path_exists(&from_slot) {
+            anyhow::bail!("{0} already exists, remove it to switch profiles", from_slot);
+        }
+        transaction.create_dir(&from_slot)?;
+        let installed = InstallDb::load(&self.lc_path);
+        // Installs recorded before bepinex.json existed keep BepInEx's record in installed.json, which is about to be swapped out.
+        installed.save_bepinex(&self.lc_path)?;
+        for plugin in installed.plugins.values().filter(|plugin| plugin.root) {
+            for file in plugin.files.iter().filter(|file| !bepinex_owned(&file.path)) {
+                let kept = format!("{0}/root/{1}", from_slot, file.path);
+                create_parent(&kept)?;
+                transaction.relocate(&format!("{0}/{1}", bepinex, file.path), &kept)?;
+            }
+        }
+        transaction.relocate(&format!("{0}/plugins", bepinex), &format!("{0}/plugins", from_slot))?;
+        transaction.relocate(&db_path(&self.lc_path), &format!("{0}/installed.json", from_slot))?;
+        transaction.relocate(&defaults, &format!("{0}/defaults", from_slot))?;
+        transaction.relocate(&selections_path(&self.lc_path), &format!("{0}/selection.json", from_slot))?;
+
+        // Move the other profile's plugins in, a profile that was never synced starts empty.
+        let to_slot = slot_dir(&self.lc_path, to);
+        if path_exists(&format!("{0}/plugins", to_slot)) {
+            transaction.relocate(&format!("{0}/plugins", to_slot), &format!("{0}/plugins", bepinex))?;
+        } else {
+            transaction.create_dir(&format!("{0}/plugins", bepinex))?;
+        }
+        transaction.relocate(&format!("{0}/installed.json", to_slot), &db_path(&self.lc_path))?;
+        transaction.relocate(&format!("{0}/defaults", to_slot), &defaults)?;
+        transaction.relocate(&format!("{0}/selection.json", to_slot), &selections_path(&self.lc_path))?;
+        let root = format!("{0}/root", to_slot);
+        if path_exists(&root) {
+            for file in scan(&root, "")? {
+                let dest = format!("{0}/{1}", bepinex, file.path);
+                if path_exists(&dest) {
+                    println!("Conflict: replacing BepInEx/{0}, which isn't part of {1}", file.path, from);
+                    transaction.remove(&dest)?;
+                }
+                create_parent(&dest)?;
+                transaction.relocate(&format!("{0}/{1}", root, file.path), &dest)?;
+            }
+        }
+
+        // Switch the active profile along with the plugins.
+        let staged = format!("{0}/profiles.json", transaction.staging_dir());
+        let mut state = self.state.clone();
+        state.active = Some(to.to_string());
+        write_state(&state, &staged)?;
+        transaction.place(&staged, &profiles_path(&self.lc_path))
+    }
+}
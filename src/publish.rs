@@ -0,0 +1,198 @@
+use anyhow::{Context, Result};
+use crate::grab::{Plugin, Plugins};
+use crate::installed::{overlaps, SHARED_DIRS};
+use crate::manifest::{BepInExRelease, Manifest, Pack};
+use crate::resolve::{check, order, with_dependencies};
+use crate::selection::Selections;
+use crate::sign::sign_file;
+use crate::util::{path_exists, sha256_sum};
+use flate2::read::GzDecoder;
+use std::collections::BTreeSet;
+use std::fs::{File, read_dir, write};
+use std::path::{Component, Path};
+use tar::{Archive, EntryType};
+
+// The top-level files and folders an archive unpacks, relative to where it is unpacked.
+struct Contents {
+    files: BTreeSet<String>,
+    folders: BTreeSet<String>
+}
+
+// Read the entries of a plugin archive and work out which paths it owns.
+fn read_contents(path: &str, root: bool) -> Result<Contents> {
+    let file = File::open(path).with_context(|| format!("Could not open {0}", path))?;
+    let mut archive = Archive::new(GzDecoder::new(file));
+    let mut contents = Contents { files: BTreeSet::new(), folders: BTreeSet::new() };
+
+    for entry in archive.entries().with_context(|| format!("Could not read {0}", path))? {
+        let entry = entry.with_context(|| format!("Could not read an entry of {0}", path))?;
+        let entry_path = entry.path().with_context(|| format!("Could not read an entry path of {0}", path))?.into_owned();
+
+        let mut components: Vec<String> = vec![];
+        for component in entry_path.components() {
+            match component {
+                Component::Normal(name) => components.push(name.to_string_lossy().to_string()),
+                Component::CurDir => {}
+                _ => anyhow::bail!("{0} contains an unsafe path: {1}", path, entry_path.display())
+            }
+        }
+        if components.is_empty() {
+            continue;
+        }
+
+        // Root plugins share BepInEx's own directories, so they own the path one level further down rather than the directories themselves.
+        let depth = if root && SHARED_DIRS.contains(&components[0].as_str()) { 2 } else { 1 };
+        let is_dir = entry.header().entry_type() == EntryType::Directory;
+        if components.len() > depth || (components.len() == depth && is_dir) {
+            contents.folders.insert(components[..depth.min(components.len())].join("/"));
+        } else if components.len() == depth {
+            contents.files.insert(components.join("/"));
+        }
+    }
+
+    Ok(contents)
+}
+
+// True if an archive looks like it has to be unpacked into BepInEx/ rather than BepInEx/plugins.
+fn looks_like_root(contents: &Contents) -> bool {
+    contents.folders.iter().any(|folder| SHARED_DIRS.contains(&folder.as_str()))
+}
+
+// Every path a plugin owns, relative to BepInEx/, for conflict checks.
+fn owned_paths(plugin: &Plugin) -> Vec<String> {
+    plugin.files.iter().flatten().chain(plugin.folders.iter().flatten())
+        .map(|path| if plugin.root { path.clone() } else { format!("plugins/{0}", path) })
+        .collect()
+}
+
+// Build plugins.json and plugins.sha256 for every plugin archive in `dir`, optionally signing the result.
+pub fn publish(dir: &str, roots: &[String], key: Option<&str>) -> Result<()> {
+    let manifest_path = format!("{0}/plugins.json", dir);
+
+    // Versions, the root flag, user-editable defaults, dependencies, conflicts and selection come from the previous manifest where there is one.
+    // The modpack's name, version and minimum client version are kept as well.
+    let (mut pack, previous) = if path_exists(&manifest_path) {
+        let previous = Manifest::load(&manifest_path).context("Could not parse previous plugins.json")?;
+        (previous.pack, previous.plugins)
+    } else {
+        (Pack::default(), vec![])
+    };
+
+    // BepInEx.tar.gz is the modpack's BepInEx build, versioned like a plugin. A BepInEx hosted elsewhere is left as it is.
+    let bepinex_path = format!("{0}/BepInEx.tar.gz", dir);
+    if path_exists(&bepinex_path) {
+        let sha256 = sha256_sum(&bepinex_path).context("Could not compute checksum for BepInEx.tar.gz")?;
+        let version = match &pack.bepinex {
+            Some(old) => {
+                if old.sha256 != sha256 {
+                    println!("BepInEx changed but is still version {0}, update bepinex.version in plugins.json if this is a new build", old.version);
+                }
+                old.version.clone()
+            }
+            None => {
+                println!("BepInEx is new, set bepinex.version in plugins.json (defaulting to 0.0.0)");
+                "0.0.0".to_string()
+            }
+        };
+        let url = pack.bepinex.as_ref().and_then(|old| old.url.clone());
+        pack.bepinex = Some(BepInExRelease { version, sha256, url });
+    }
+
+    let mut tar_names: Vec<String> = vec![];
+    for entry in read_dir(dir).with_context(|| format!("Could not read {0}", dir))? {
+        let name = entry.context("Could not read directory entry")?.file_name().to_string_lossy().to_string();
+        if name.ends_with(".tar.gz") && name != "BepInEx.tar.gz" {
+            tar_names.push(name);
+        }
+    }
+
+    let mut plugins: Plugins = vec![];
+    for tar_name in tar_names {
+        let tar_path = format!("{0}/{1}", dir, tar_name);
+        let old = previous.iter().find(|plugin| plugin.tar_name == tar_name);
+        let identifier = match old {
+            Some(old) => old.identifier.clone(),
+            None => tar_name.trim_end_matches(".tar.gz").to_string()
+        };
+        let sha256 = sha256_sum(&tar_path).with_context(|| format!("Could not compute checksum for {0}", tar_name))?;
+
+        let mut root = old.map(|old| old.root).unwrap_or(false) || roots.contains(&identifier);
+        let mut contents = read_contents(&tar_path, root)?;
+        if !root && old.is_none() && looks_like_root(&contents) {
+            println!("{0} contains BepInEx directories, publishing it as a root plugin", identifier);
+            root = true;
+            contents = read_contents(&tar_path, root)?;
+        }
+
+        let version = match old {
+            Some(old) => {
+                if old.sha256 != sha256 {
+                    println!("{0} changed but is still version {1}, update its version in plugins.json if this is an upgrade", identifier, old.version);
+                }
+                old.version.clone()
+            }
+            None => {
+                println!("{0} is new, set its version in plugins.json (defaulting to 0.0.0)", identifier);
+                "0.0.0".to_string()
+            }
+        };
+
+        plugins.push(Plugin {
+            identifier,
+            sha256,
+            version,
+            tar_name,
+            files: if contents.files.is_empty() { None } else { Some(contents.files.into_iter().collect()) },
+            folders: if contents.folders.is_empty() { None } else { Some(contents.folders.into_iter().collect()) },
+            defaults: old.and_then(|old| old.defaults.clone()),
+            dependencies: old.and_then(|old| old.dependencies.clone()),
+            conflicts: old.and_then(|old| old.conflicts.clone()),
+            selection: old.map(|old| old.selection).unwrap_or_default(),
+            source: None,
+            root
+        });
+    }
+
+    // The client's merge walks both manifests in identifier order.
+    plugins.sort_by_key(|plugin| plugin.identifier.to_lowercase());
+
+    let mut conflicts: Vec<String> = vec![];
+    for (index, plugin) in plugins.iter().enumerate() {
+        if index > 0 && plugins[index - 1].identifier.to_lowercase() == plugin.identifier.to_lowercase() {
+            conflicts.push(format!("{0} and {1} have the same identifier", plugins[index - 1].tar_name, plugin.tar_name));
+        }
+        for other in &plugins[index + 1..] {
+            for path in owned_paths(plugin) {
+                if let Some(other_path) = owned_paths(other).iter().find(|other_path| overlaps(&path, other_path)) {
+                    conflicts.push(format!("{0} ({1}) and {2} ({3}) install the same path", plugin.identifier, path, other.identifier, other_path));
+                }
+            }
+        }
+    }
+    if !conflicts.is_empty() {
+        anyhow::bail!("Refusing to publish because of conflicts:\n{0}", conflicts.join("\n"));
+    }
+    // Checked like a player who made no choices would sync, optional plugins may depend on or exclude things the rest don't.
+    let defaults = Selections::default();
+    let mut wanted: Vec<bool> = plugins.iter().map(|plugin| defaults.wants(plugin)).collect();
+    with_dependencies(&plugins, &mut wanted, |_, _| {});
+    let selected: Plugins = plugins.iter().zip(wanted).filter(|(_, wanted)| *wanted).map(|(plugin, _)| plugin.clone()).collect();
+    check(&selected).context("Refusing to publish")?;
+    order(&plugins).context("Refusing to publish")?;
+
+    let count = plugins.len();
+    Manifest::new(pack, plugins).write_to(&manifest_path)?;
+    let manifest_sha256 = sha256_sum(&manifest_path)?;
+    write(format!("{0}/plugins.sha256", dir), &manifest_sha256).context("Could not write plugins.sha256")?;
+    println!("Wrote {0} plugins to {1}", count, manifest_path);
+
+    let signature_path = Path::new(&manifest_path).with_file_name("plugins.sig").display().to_string();
+    if let Some(key) = key {
+        sign_file(&manifest_path, key, &signature_path)?;
+        println!("Signed {0}", manifest_path);
+    } else if path_exists(&signature_path) {
+        println!("Warning: {0} no longer matches plugins.json, sign it again", signature_path);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use anyhow::Result;
+use crate::grab::Plugin;
+use crate::util::LCError;
+use crate::version::parse;
+use semver::VersionReq;
+use std::collections::BTreeMap;
+
+fn find<'a>(plugins: &'a [Plugin], identifier: &str) -> Option<&'a Plugin> {
+    plugins.iter().find(|plugin| plugin.identifier.eq_ignore_ascii_case(identifier))
+}
+
+// True if the plugin's version is in the range. Ranges use Cargo's syntax, e.g. ">=0.10, <1" or "*".
+fn satisfies(plugin: &Plugin, range: &str) -> Result<bool> {
+    let req = VersionReq::parse(range).map_err(|err| anyhow::anyhow!("invalid version range \"{0}\": {1}", range, err))?;
+    let version = parse(&plugin.version)
+        .ok_or_else(|| anyhow::anyhow!("{0} has a version that can't be compared: \"{1}\"", plugin.identifier, plugin.version))?;
+    Ok(req.matches(&version))
+}
+
+// Check every dependency and conflict between the plugins, so a sync or publish is refused before anything is downloaded or written.
+pub fn check(plugins: &[Plugin]) -> Result<()> {
+    let mut problems: Vec<String> = vec![];
+    for plugin in plugins {
+        for (identifier, range) in plugin.dependencies.iter().flatten() {
+            match find(plugins, identifier) {
+                None => problems.push(format!("{0} depends on {1} {2}, which isn't in the modpack", plugin.identifier, identifier, range)),
+                Some(dependency) => match satisfies(dependency, range) {
+                    Ok(true) => {}
+                    Ok(false) => problems.push(format!("{0} depends on {1} {2}, but the modpack has {1} {3}", plugin.identifier, dependency.identifier, range, dependency.version)),
+                    Err(err) => problems.push(format!("{0} depends on {1}: {2}", plugin.identifier, identifier, err))
+                }
+            }
+        }
+
+        for (identifier, range) in plugin.conflicts.iter().flatten() {
+            let Some(other) = find(plugins, identifier) else { continue };
+            match satisfies(other, range) {
+                Ok(true) => problems.push(format!("{0} conflicts with {1} {2}, and the modpack has {1} {3}", plugin.identifier, other.identifier, range, other.version)),
+                Ok(false) => {}
+                Err(err) => problems.push(format!("{0} conflicts with {1}: {2}", plugin.identifier, identifier, err))
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(LCError::Dependency(format!("The modpack's dependencies can't be satisfied:\n{0}", problems.join("\n"))).into());
+    }
+    Ok(())
+}
+
+// Extend the wanted plugins (one flag per plugin) with everything they depend on, directly or not.
+// `pulled` is told about each plugin added and the plugin that needed it.
+pub fn with_dependencies(plugins: &[Plugin], wanted: &mut [bool], mut pulled: impl FnMut(&Plugin, &Plugin)) {
+    loop {
+        let mut changed = false;
+        for index in 0..plugins.len() {
+            if !wanted[index] {
+                continue;
+            }
+            for identifier in plugins[index].dependencies.iter().flatten().map(|(identifier, _)| identifier) {
+                if let Some(dependency) = plugins.iter().position(|plugin| plugin.identifier.eq_ignore_ascii_case(identifier)) {
+                    if !wanted[dependency] {
+                        pulled(&plugins[dependency], &plugins[index]);
+                        wanted[dependency] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+}
+
+// Rank each plugin (by lowercase identifier) so every plugin comes after the plugins it depends on, ties broken alphabetically.
+// Dependencies on plugins that aren't in the list are ignored.
+pub fn order(plugins: &[Plugin]) -> Result<BTreeMap<String, usize>> {
+    let mut remaining: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for plugin in plugins {
+        let dependencies = plugin.dependencies.iter().flatten()
+            .map(|(identifier, _)| identifier.to_lowercase())
+            .filter(|identifier| find(plugins, identifier).is_some())
+            .collect();
+        remaining.insert(plugin.identifier.to_lowercase(), dependencies);
+    }
+
+    let mut ranks: BTreeMap<String, usize> = BTreeMap::new();
+    while !remaining.is_empty() {
+        let Some(next) = remaining.iter()
+            .find(|(_, dependencies)| dependencies.iter().all(|dependency| ranks.contains_key(dependency)))
+            .map(|(identifier, _)| identifier.clone())
+        else {
+            let cycle: Vec<&str> = remaining.keys().map(|identifier| identifier.as_str()).collect();
+            return Err(LCError::Dependency(format!("Plugins depend on each other in a cycle: {0}", cycle.join(", "))).into());
+        };
+        remaining.remove(&next);
+        ranks.insert(next, ranks.len());
+    }
+
+    Ok(ranks)
+}
@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+use crate::grab::Plugin;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read_to_string, write};
+
+// Whether players can choose to leave a plugin out.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Selection {
+    // Always installed.
+    #[default]
+    Required,
+    // Installed unless the player disables it.
+    DefaultOn,
+    // Only installed if the player enables it.
+    Optional
+}
+
+impl Selection {
+    pub fn is_required(&self) -> bool {
+        *self == Selection::Required
+    }
+}
+
+// The player's choices for optional and default-on plugins, keyed by lowercase identifier.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Selections {
+    plugins: BTreeMap<String, bool>
+}
+
+pub fn selections_path(lc_path: &str) -> String {
+    format!("{0}/.lcmodmanager/selection.json", lc_path)
+}
+
+impl Selections {
+    // Load the player's choices, a missing file means none were made.
+    pub fn load(lc_path: &str) -> Result<Selections> {
+        let path = selections_path(lc_path);
+        match read_to_string(&path) {
+            Ok(selections) => serde_json::from_str(&selections).with_context(|| format!("Could not parse {0}", path)),
+            Err(_) => Ok(Selections::default())
+        }
+    }
+
+    pub fn save(&self, lc_path: &str) -> Result<()> {
+        create_dir_all(format!("{0}/.lcmodmanager", lc_path)).context("Could not create .lcmodmanager")?;
+        let selections = serde_json::to_string_pretty(self).context("Could not serialize plugin selection")?;
+        write(selections_path(lc_path), selections).context("Could not write plugin selection")
+    }
+
+    pub fn set(&mut self, identifier: &str, enabled: bool) {
+        self.plugins.insert(identifier.to_lowercase(), enabled);
+    }
+
+    // True if the plugin should be installed.
+    pub fn wants(&self, plugin: &Plugin) -> bool {
+        let choice = self.plugins.get(&plugin.identifier.to_lowercase()).copied();
+        match plugin.selection {
+            Selection::Required => true,
+            Selection::DefaultOn => choice.unwrap_or(true),
+            Selection::Optional => choice.unwrap_or(false)
+        }
+    }
+}
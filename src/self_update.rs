@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use crate::events;
+use crate::manifest::{Binary, ClientRelease};
+use crate::util::{confirm, get_bytes, path_exists, sha256_sum, LCError};
+use crate::version::compare;
+use std::cmp::Ordering;
+use std::env::{consts, current_exe};
+use std::fs::{remove_file, rename, write};
+use std::process::Command;
+
+// What to do during a sync when the modpack offers a newer lcmodmanager.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum SelfUpdatePolicy {
+    Ask,
+    Never
+}
+
+// This platform's key in a release's binaries, e.g. linux-x86_64.
+pub fn platform_key() -> String {
+    format!("{0}-{1}", consts::OS, consts::ARCH)
+}
+
+// The build of the release for this platform, if it is newer than this lcmodmanager.
+pub fn newer(release: &ClientRelease) -> Option<&Binary> {
+    if compare(&release.version, env!("CARGO_PKG_VERSION")) != Some(Ordering::Greater) {
+        return None;
+    }
+    release.binaries.get(&platform_key())
+}
+
+// Refuse to install a release from a manifest whose signature wasn't verified.
+pub fn require_verified(verified: bool) -> Result<()> {
+    if verified {
+        return Ok(());
+    }
+    Err(LCError::SignatureInvalid("no trusted keys are configured, set TRUSTEDKEYS to update lcmodmanager from the modpack".to_string()).into())
+}
+
+// Replace the running executable with the release. The new binary is downloaded and verified next to the old one and swapped in
+// with renames, and the old one is put back if the new one can't report its version.
+pub fn install(release: &ClientRelease, binary: &Binary) -> Result<()> {
+    let exe = current_exe().context("Could not find the lcmodmanager executable")?.display().to_string();
+    let new = format!("{0}.new", exe);
+    let old = format!("{0}.old", exe);
+
+    // Windows can't delete a running executable, so the previous update's is cleaned up now.
+    if path_exists(&old) {
+        remove_file(&old).with_context(|| format!("Could not remove {0}", old))?;
+    }
+
+    events::info(&format!("Downloading lcmodmanager {0}", release.version));
+    let body = get_bytes(&binary.url).with_context(|| format!("Could not download lcmodmanager {0}", release.version))?;
+    write(&new, body).with_context(|| format!("Could not write {0}", new))?;
+    let sha256 = sha256_sum(&new)?;
+    if sha256 != binary.sha256 {
+        let _ = remove_file(&new);
+        return Err(LCError::CheckSumDiscrepency { name: format!("lcmodmanager {0}", release.version), expected: binary.sha256.clone(), actual: sha256 }.into());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&new, std::fs::Permissions::from_mode(0o755)).with_context(|| format!("Could not make {0} executable", new))?;
+    }
+
+    rename(&exe, &old).with_context(|| format!("Could not move {0} aside", exe))?;
+    if let Err(err) = rename(&new, &exe) {
+        rename(&old, &exe).with_context(|| format!("Could not restore {0} from {1}", exe, old))?;
+        return Err(err).with_context(|| format!("Could not replace {0}", exe));
+    }
+
+    let reported = Command::new(&exe).arg("--version").output().ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    if !reported.as_deref().is_some_and(|reported| reported.ends_with(&release.version)) {
+        rename(&exe, &new).with_context(|| format!("Could not move the new {0} aside", exe))?;
+        rename(&old, &exe).with_context(|| format!("Could not restore {0} from {1}", exe, old))?;
+        let _ = remove_file(&new);
+        anyhow::bail!("lcmodmanager {0} failed its self-check, kept {1}", release.version, env!("CARGO_PKG_VERSION"));
+    }
+
+    // Fails on Windows while this process is still running, the next update removes it.
+    let _ = remove_file(&old);
+    events::info(&format!("Updated lcmodmanager {0} -> {1}", env!("CARGO_PKG_VERSION"), release.version));
+    Ok(())
+}
+
+// Offer to update after a sync, a failed update is only a warning since the plugins are already in place.
+// The binary's url and sha256 are only as trustworthy as the manifest, so nothing is installed unless its signature was verified.
+pub fn offer(release: Option<&ClientRelease>, verified: bool, assume_yes: bool) -> Result<()> {
+    let Some(release) = release else { return Ok(()) };
+    let Some(binary) = newer(release) else { return Ok(()) };
+    if !verified {
+        events::warn(&format!("lcmodmanager {0} is available, but plugins.json isn't signed by a trusted key so it won't be installed", release.version));
+        return Ok(());
+    }
+    if !assume_yes && !confirm(&format!("lcmodmanager {0} is available (this is {1}), update now?", release.version, env!("CARGO_PKG_VERSION")))? {
+        return Ok(());
+    }
+    if let Err(err) = install(release, binary) {
+        events::warn(&format!("could not update lcmodmanager: {0:#}", err));
+    }
+    Ok(())
+}
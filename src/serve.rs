@@ -0,0 +1,224 @@
+use anyhow::{Context, Result};
+use crate::util::{path_exists, sha256_sum};
+use std::collections::HashMap;
+use std::fs::{File, metadata, read_to_string, write};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path};
+use std::sync::Mutex;
+use std::thread;
+use std::time::SystemTime;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+// Requests handled at once, enough for clients downloading plugins in parallel.
+const WORKERS: usize = 8;
+
+// The manifest and its checksum and signature, and the checksum BEPINEXSHA256 points at, served alongside the archives.
+const MANIFEST_FILES: [&str; 4] = ["plugins.json", "plugins.sha256", "plugins.sig", "BepInEx.sha256"];
+
+// Serves a modpack directory (plugins.json, plugins.sha256, BepInEx.tar.gz, BepInEx.sha256 and plugin archives) over HTTP.
+pub struct ModpackServer {
+    dir: String,
+    // sha256 of each served file, keyed by path and invalidated when its size or modification time changes.
+    etags: Mutex<HashMap<String, (SystemTime, u64, String)>>,
+    // Modification time of plugins.json when plugins.sha256 was last brought up to date.
+    manifest_modified: Mutex<Option<SystemTime>>
+}
+
+// A byte range requested with the Range header, end inclusive.
+enum Range {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn request_header<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request.headers().iter()
+        .find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_str())
+}
+
+// Parses a single `bytes=start-end`, `bytes=start-` or `bytes=-suffix` range, anything else is served in full.
+fn parse_range(value: &str, length: u64) -> Range {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else { return Range::Full };
+    if spec.contains(',') {
+        return Range::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else { return Range::Full };
+
+    let (start, end) = match (start.trim().parse::<u64>().ok(), end.trim().parse::<u64>().ok()) {
+        (Some(start), Some(end)) => (start, end.min(length.saturating_sub(1))),
+        (Some(start), None) if end.trim().is_empty() => (start, length.saturating_sub(1)),
+        (None, Some(suffix)) if start.trim().is_empty() && suffix > 0 => (length.saturating_sub(suffix), length.saturating_sub(1)),
+        _ => return Range::Full
+    };
+
+    if length == 0 || start > end || start >= length {
+        Range::Unsatisfiable
+    } else {
+        Range::Partial(start, end)
+    }
+}
+
+// The files that make up a modpack, nothing else in the directory (keys, dotfiles, notes) is served.
+fn servable(name: &str) -> bool {
+    MANIFEST_FILES.contains(&name) || (name.ends_with(".tar.gz") && !name.starts_with('.'))
+}
+
+// Decode %XX escapes in a URL path, None if one is malformed or the result isn't UTF-8.
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes: Vec<u8> = vec![];
+    let mut input = path.bytes();
+    while let Some(byte) = input.next() {
+        if byte == b'%' {
+            let hex = [input.next()?, input.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn content_type(name: &str) -> &'static str {
+    if name.ends_with(".json") {
+        "application/json"
+    } else if name.ends_with(".tar.gz") {
+        "application/gzip"
+    } else {
+        "text/plain; charset=utf-8"
+    }
+}
+
+impl ModpackServer {
+    pub fn new(dir: &str) -> Result<ModpackServer> {
+        if !path_exists(&format!("{0}/plugins.json", dir)) {
+            anyhow::bail!("{0} does not contain a plugins.json", dir);
+        }
+        Ok(ModpackServer { dir: dir.to_string(), etags: Mutex::new(HashMap::new()), manifest_modified: Mutex::new(None) })
+    }
+
+    // Rewrite plugins.sha256 whenever plugins.json has changed since it was last checked.
+    fn refresh_checksum(&self) -> Result<()> {
+        let manifest = format!("{0}/plugins.json", self.dir);
+        let modified = metadata(&manifest).and_then(|meta| meta.modified()).context("Could not read plugins.json")?;
+        let mut manifest_modified = self.manifest_modified.lock().unwrap();
+        if *manifest_modified == Some(modified) {
+            return Ok(());
+        }
+
+        let checksum_path = format!("{0}/plugins.sha256", self.dir);
+        let checksum = sha256_sum(&manifest)?;
+        let old_checksum = read_to_string(&checksum_path).ok();
+        if old_checksum.as_deref().map(|old| old.trim() != checksum).unwrap_or(true) {
+            write(&checksum_path, &checksum).context("Could not write plugins.sha256")?;
+            println!("Wrote plugins.sha256 {0}", checksum);
+            if old_checksum.is_some() && path_exists(&format!("{0}/plugins.sig", self.dir)) {
+                println!("Warning: plugins.sig may no longer match plugins.json, sign it again");
+            }
+        }
+        *manifest_modified = Some(modified);
+        Ok(())
+    }
+
+    // The quoted ETag for a file, its sha256.
+    fn etag(&self, path: &str, modified: SystemTime, size: u64) -> Result<String> {
+        if let Some((cached_modified, cached_size, etag)) = self.etags.lock().unwrap().get(path) {
+            if *cached_modified == modified && *cached_size == size {
+                return Ok(etag.clone());
+            }
+        }
+
+        let etag = format!("\"{0}\"", sha256_sum(path)?);
+        self.etags.lock().unwrap().insert(path.to_string(), (modified, size, etag.clone()));
+        Ok(etag)
+    }
+
+    // Map a request URL to a modpack file in the directory, refusing anything that escapes it or isn't part of the modpack.
+    fn resolve(&self, url: &str) -> Option<String> {
+        let path = percent_decode(url.split(['?', '#']).next().unwrap_or(""))?;
+        let path = path.trim_start_matches('/');
+        let mut components = Path::new(path).components();
+        // Modpack files all sit at the top of the directory.
+        let (Some(Component::Normal(name)), None) = (components.next(), components.next()) else { return None };
+        if !servable(&name.to_string_lossy()) {
+            return None;
+        }
+        let full = format!("{0}/{1}", self.dir, path);
+        metadata(&full).ok().filter(|meta| meta.is_file()).map(|_| full)
+    }
+
+    fn handle(&self, request: Request) -> Result<()> {
+        self.refresh_checksum()?;
+
+        if *request.method() != Method::Get && *request.method() != Method::Head {
+            return request.respond(Response::empty(StatusCode(405)).with_header(header("Allow", "GET, HEAD")))
+                .context("Could not send response");
+        }
+        let Some(path) = self.resolve(request.url()) else {
+            return request.respond(Response::empty(StatusCode(404))).context("Could not send response");
+        };
+
+        let meta = metadata(&path).context("Could not read file")?;
+        let length = meta.len();
+        let etag = self.etag(&path, meta.modified().unwrap_or(SystemTime::UNIX_EPOCH), length)?;
+
+        if request_header(&request, "If-None-Match").map(|value| value.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*")).unwrap_or(false) {
+            return request.respond(Response::empty(StatusCode(304)).with_header(header("ETag", &etag)))
+                .context("Could not send response");
+        }
+
+        // If-Range only allows a partial response when the client still has the same version.
+        let range_allowed = request_header(&request, "If-Range").map(|value| value.trim() == etag).unwrap_or(true);
+        let range = match request_header(&request, "Range") {
+            Some(value) if range_allowed => parse_range(value, length),
+            _ => Range::Full
+        };
+
+        let mut file = File::open(&path).context("Could not open file")?;
+        let headers = vec![
+            header("Content-Type", content_type(&path)),
+            header("ETag", &etag),
+            header("Accept-Ranges", "bytes")
+        ];
+        let response = match range {
+            Range::Full => Response::new(StatusCode(200), headers, Box::new(file) as Box<dyn Read + Send>, Some(length as usize), None),
+            Range::Partial(start, end) => {
+                file.seek(SeekFrom::Start(start)).context("Could not seek file")?;
+                Response::new(StatusCode(206), headers, Box::new(file.take(end - start + 1)) as Box<dyn Read + Send>, Some((end - start + 1) as usize), None)
+                    .with_header(header("Content-Range", &format!("bytes {0}-{1}/{2}", start, end, length)))
+            }
+            Range::Unsatisfiable => {
+                Response::new(StatusCode(416), headers, Box::new(std::io::empty()) as Box<dyn Read + Send>, Some(0), None)
+                    .with_header(header("Content-Range", &format!("bytes */{0}", length)))
+            }
+        };
+
+        request.respond(response).context("Could not send response")
+    }
+
+    // Serve requests until the process is stopped.
+    pub fn run(&self, address: &str) -> Result<()> {
+        self.refresh_checksum()?;
+        let server = Server::http(address).map_err(|err| anyhow::anyhow!("Could not listen on {0}: {1}", address, err))?;
+        println!("Serving {0} on http://{1}", self.dir, address);
+
+        thread::scope(|scope| {
+            for _ in 0..WORKERS {
+                scope.spawn(|| {
+                    while let Ok(request) = server.recv() {
+                        let url = request.url().to_string();
+                        if let Err(err) = self.handle(request) {
+                            println!("Error serving {0}: {1:#}", url, err);
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+}
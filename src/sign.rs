@@ -0,0 +1,59 @@
+use anyhow::{Context, Result};
+use crate::util::{path_exists, LCError};
+use data_encoding::BASE64;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use std::fs::{read, read_to_string, write};
+
+// Splits a comma or whitespace separated list of base64 public keys.
+pub fn parse_keys(keys: &str) -> Vec<String> {
+    keys.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|key| !key.is_empty())
+        .map(|key| key.to_string())
+        .collect()
+}
+
+fn load_key_pair(key_path: &str) -> Result<Ed25519KeyPair> {
+    let key_str = read_to_string(key_path).with_context(|| format!("Could not read signing key: {0}", key_path))?;
+    let pkcs8 = BASE64.decode(key_str.trim().as_bytes()).with_context(|| format!("Signing key is not base64: {0}", key_path))?;
+    Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| anyhow::anyhow!("Signing key is not an ed25519 PKCS#8 key: {0}", key_path))
+}
+
+// Create a new signing key at `key_path` and return its public key.
+pub fn generate_key(key_path: &str) -> Result<String> {
+    if path_exists(key_path) {
+        anyhow::bail!("Refusing to overwrite existing signing key: {0}", key_path);
+    }
+
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| anyhow::anyhow!("Could not generate signing key"))?;
+    write(key_path, BASE64.encode(pkcs8.as_ref())).with_context(|| format!("Could not write signing key: {0}", key_path))?;
+    public_key(key_path)
+}
+
+// The base64 public key for a signing key, this is what clients trust.
+pub fn public_key(key_path: &str) -> Result<String> {
+    Ok(BASE64.encode(load_key_pair(key_path)?.public_key().as_ref()))
+}
+
+// Write a detached base64 signature of `path` to `signature_path`.
+pub fn sign_file(path: &str, key_path: &str, signature_path: &str) -> Result<()> {
+    let key_pair = load_key_pair(key_path)?;
+    let contents = read(path).with_context(|| format!("Could not read {0}", path))?;
+    let signature = key_pair.sign(&contents);
+    write(signature_path, BASE64.encode(signature.as_ref())).with_context(|| format!("Could not write {0}", signature_path))
+}
+
+// Succeeds if `signature` is a valid signature of `contents` by any of the trusted keys.
+pub fn verify(contents: &[u8], signature: &str, trusted_keys: &[String]) -> Result<()> {
+    let signature = BASE64.decode(signature.trim().as_bytes())
+        .map_err(|_| LCError::SignatureInvalid("plugins.sig is not base64".to_string()))?;
+
+    for key in trusted_keys {
+        let Ok(key) = BASE64.decode(key.as_bytes()) else { continue };
+        if UnparsedPublicKey::new(&ED25519, key).verify(contents, &signature).is_ok() {
+            return Ok(());
+        }
+    }
+
+    Err(LCError::SignatureInvalid("plugins.json is not signed by a trusted key".to_string()).into())
+}
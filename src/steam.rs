@@ -1,27 +1,187 @@
-use anyhow::{Context, Result, Ok};
-use crate::util::{path_exists, sha256_sum};
-use std::fs::{File, write, remove_file, read_to_string};
-use reqwest::blocking::get;
+use anyhow::{Context, Result};
+use crate::discover::APP_ID;
+use crate::events;
+use crate::transaction::Transaction;
+use crate::util::{confirm, get_bytes, path_exists, sha256_sum, LCError};
+use crate::vdf::{self, Vdf};
+use clap::ValueEnum;
+use std::fs::{canonicalize, copy, create_dir_all, read_dir, rename, File, write, remove_file, read_to_string};
 use flate2::read::GzDecoder;
 use tar::Archive;
 use std::process::Command;
+use std::time::{Duration, Instant};
+
+// How often to check whether the game's first launch has set up BepInEx, and to say it is still waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(15);
+
+// Doorstop, which loads BepInEx into the game, relative to the game folder.
+const DOORSTOP_FILES: [&str; 2] = ["winhttp.dll", "doorstop_config.ini"];
+
+// What the player and their plugins put in BepInEx, kept when BepInEx itself is replaced.
+const KEPT_DIRS: [&str; 3] = ["BepInEx/plugins", "BepInEx/config", "BepInEx/patchers"];
+
+// How a fresh BepInEx install is finished: by launching the game once so BepInEx sets itself up,
+// or by creating its folders directly and leaving the rest to the player's first launch.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Bootstrap {
+    Launch,
+    Layout
+}
 
 pub struct Steam {
     pub lc_path: String,
     pub run_command: String,
     pub bepinex_download: String,
     pub bepinex_sha256: String,
-    pub flatpak: bool
+    pub flatpak: bool,
+    // Steam roots whose accounts run the game under Proton, empty on Windows
+    pub proton_roots: Vec<String>,
+    pub bootstrap: Bootstrap,
+    pub timeout: Duration
+}
+
+// Proton only loads BepInEx's winhttp.dll proxy with this in the game's launch options.
+const WINHTTP_OVERRIDE: &str = "WINEDLLOVERRIDES=\"winhttp=n,b\"";
+
+// A Steam account's localconfig.vdf and the game's launch options in it.
+struct LaunchOptions {
+    path: String,
+    current: String
+}
+
+impl LaunchOptions {
+    fn has_override(&self) -> bool {
+        self.current.contains("winhttp=n")
+    }
+
+    // Overrides for other DLLs would have to be merged by hand.
+    fn can_add(&self) -> bool {
+        !self.current.contains("WINEDLLOVERRIDES")
+    }
+
+    fn with_override(&self) -> String {
+        let current = self.current.trim();
+        if current.contains("%command%") {
+            format!("{0} {1}", WINHTTP_OVERRIDE, current)
+        } else {
+            // Without %command% the options are arguments to the game.
+            format!("{0} %command% {1}", WINHTTP_OVERRIDE, current).trim_end().to_string()
+        }
+    }
+
+    // Back the file up next to itself, then replace it with the launch option added. Only the game's existing settings are changed.
+    fn add_override(&self) -> Result<String> {
+        let text = read_to_string(&self.path).with_context(|| format!("Could not read {0}", self.path))?;
+        // A file that can't be parsed is left alone, saving a partial tree would lose the rest of Steam's settings.
+        let mut config = vdf::parse(&text).with_context(|| format!("Could not parse {0}", self.path))?;
+        let app = ["UserLocalConfigStore", "Software", "Valve", "Steam", "apps", APP_ID].iter()
+            .try_fold(&mut config, |node, key| node.object_mut(key))
+            .with_context(|| format!("{0} has no settings for Lethal Company", self.path))?;
+        app.set("LaunchOptions", &self.with_override());
+
+        let backup = format!("{0}.lcmodmanager.bak", self.path);
+        copy(&self.path, &backup).with_context(|| format!("Could not back up {0}", self.path))?;
+        let partial = format!("{0}.part", self.path);
+        write(&partial, config.to_string()).with_context(|| format!("Could not write {0}", partial))?;
+        rename(&partial, &self.path).with_context(|| format!("Could not write {0}", self.path))?;
+        Ok(backup)
+    }
+}
+
+// The game's launch options for every Steam account under a root that has Lethal Company settings. Accounts that never
+// ran the game are left alone, their localconfig.vdf is none of lcmodmanager's business.
+fn launch_options(roots: &[String]) -> Vec<LaunchOptions> {
+    let mut found: Vec<LaunchOptions> = vec![];
+    let mut unplayed = 0;
+    let mut seen: Vec<std::path::PathBuf> = vec![];
+    for root in roots {
+        let Ok(users) = read_dir(format!("{0}/userdata", root)) else { continue };
+        for user in users.flatten() {
+            let path = format!("{0}/config/localconfig.vdf", user.path().display());
+            // ~/.steam/steam is usually a link to one of the other roots.
+            let Ok(canonical) = canonicalize(&path) else { continue };
+            if seen.contains(&canonical) {
+                continue;
+            }
+            seen.push(canonical);
+            let config = match vdf::load(&path) {
+                Ok(config) => config,
+                Err(err) => {
+                    events::warn(&format!("{0:#}, check that Lethal Company's launch options contain {1} by hand", err, WINHTTP_OVERRIDE));
+                    continue;
+                }
+            };
+            let Some(app) = ["UserLocalConfigStore", "Software", "Valve", "Steam", "apps", APP_ID].iter()
+                .try_fold(&config, |node, key| node.get(key)) else {
+                unplayed += 1;
+                continue;
+            };
+            let current = app.get("LaunchOptions").and_then(Vdf::value).unwrap_or_default().to_string();
+            found.push(LaunchOptions { path, current });
+        }
+    }
+    if found.is_empty() && unplayed > 0 {
+        events::info(&format!("No Steam account has launched Lethal Company yet, add {0} %command% to its launch options in Steam", WINHTTP_OVERRIDE));
+    }
+    found
+}
+
+// Steam rewrites localconfig.vdf when it exits, so it may only be edited while Steam is closed.
+fn steam_running() -> bool {
+    let Ok(processes) = read_dir("/proc") else { return false };
+    processes.flatten().any(|process| {
+        read_to_string(process.path().join("comm")).is_ok_and(|name| name.trim() == "steam")
+    })
 }
 
 impl Steam {
-    pub fn install_bepinex(&self) -> Result<()> {
-        let mut resp = get(&self.bepinex_download).context("Could not get file")?;
-        let mut body = resp.bytes().context("Could not convert file to bytes")?;
+    // Make sure Proton will load BepInEx, offering to add the launch option where it is missing.
+    pub fn check_launch_options(&self, assume_yes: bool) -> Result<()> {
+        let options = launch_options(&self.proton_roots);
+        let missing: Vec<&LaunchOptions> = options.iter().filter(|options| !options.has_override()).collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let manual = format!("add {0} %command% to Lethal Company's launch options in Steam", WINHTTP_OVERRIDE);
+        events::warn(&format!("BepInEx won't load under Proton without the launch option {0}", WINHTTP_OVERRIDE));
+        for options in missing.iter().filter(|options| !options.can_add()) {
+            events::warn(&format!("{0} already sets other DLL overrides ({1}), add winhttp=n,b to them by hand", options.path, options.current));
+        }
+        let addable: Vec<&&LaunchOptions> = missing.iter().filter(|options| options.can_add()).collect();
+        if addable.is_empty() {
+            return Ok(());
+        }
+        if steam_running() {
+            events::warn(&format!("Steam is running so its launch options can't be changed, close Steam and sync again or {0}", manual));
+            return Ok(());
+        }
+        if !assume_yes && !confirm("Add it to Lethal Company's launch options?")? {
+            events::info(&format!("Launch options left unchanged, {0}", manual));
+            return Ok(());
+        }
+
+        for options in addable {
+            match options.add_override() {
+                Ok(backup) => events::info(&format!("Added the launch option to {0} (backup at {1})", options.path, backup)),
+                Err(err) => events::warn(&format!("{0:#}, {1}", err, manual))
+            }
+        }
+        Ok(())
+    }
+
+    // Download the archive from BEPINEXDOWNLOAD for modpacks that don't name a BepInEx build, and check it against BEPINEXSHA256.
+    pub fn download_bepinex(&self) -> Result<String> {
+        if self.bepinex_download.is_empty() {
+            return Err(LCError::BepInExMissing(format!("{0} and BEPINEXDOWNLOAD isn't set", self.lc_path)).into());
+        }
+
+        events::info("Downloading BepInEx");
+        let body = get_bytes(&self.bepinex_download).context("Could not get BepInEx")?;
         write("./lc/BepInEx.tar.gz", body).context("Could not write BepInEx.tar.gz")?;
 
-        resp = get(&self.bepinex_sha256).context("Could not get BepInEx sha256 checksum")?;
-        body = resp.bytes().context("Could not convert  BepInEx sha256 checksum to bytes")?;
+        let body = get_bytes(&self.bepinex_sha256).context("Could not get BepInEx sha256 checksum")?;
         write("./lc/BepInEx.sha256", body).context("Could not write BepInEx.sha256")?;
 
         // Get the BepInEx sha256 and compare it to the local, if they aren't a match that is a problem
@@ -29,36 +189,156 @@ impl Steam {
                                             .context("Couldn't read BepInEx.sha256")?
                                             .trim().to_string();
         let bepinex_sha256_checksum = sha256_sum("./lc/BepInEx.tar.gz").context("Could not compute checksum for BepInEx.tar.gz")?;
+        remove_file("./lc/BepInEx.sha256").context("Could not remove BepInEx.sha256")?;
 
         if bepinex_sha256_checksum != bepinex_sha256_file {
-            panic!("BepInEx sha256 don't match\nServer: {0}\nClient: {1}", bepinex_sha256_file, bepinex_sha256_checksum);
+            remove_file("./lc/BepInEx.tar.gz").context("Could not remove BepInEx.tar.gz")?;
+            return Err(LCError::CheckSumDiscrepency { name: "BepInEx".to_string(), expected: bepinex_sha256_file, actual: bepinex_sha256_checksum }.into());
         }
+        Ok("./lc/BepInEx.tar.gz".to_string())
+    }
 
-        let dest: File = File::open("./lc/BepInEx.tar.gz").context("Could not open BepInEx.tar.gz")?;
-        let tar = GzDecoder::new(dest);
-        let mut archive = Archive::new(tar);
-        archive.unpack(&self.lc_path).context("Could not unpack tar")?; // Write to the lethal company steam path
+    // Unpack the archive entries `wanted` accepts into the game folder. Returns every file in the archive, relative to the game folder.
+    fn unpack_bepinex(&self, archive: &str, dest: &str, wanted: impl Fn(&str) -> bool) -> Result<Vec<String>> {
+        let file = File::open(archive).with_context(|| format!("Could not open {0}", archive))?;
+        let mut archive = Archive::new(GzDecoder::new(file));
+        let mut files: Vec<String> = vec![];
+        for entry in archive.entries().context("Could not read the BepInEx archive")? {
+            let mut entry = entry.context("Could not read an entry of the BepInEx archive")?;
+            let path = entry.path().context("Could not read an entry path of the BepInEx archive")?
+                .to_string_lossy().trim_start_matches("./").trim_end_matches('/').to_string();
+            if path.is_empty() {
+                continue;
+            }
+            if entry.header().entry_type().is_file() {
+                files.push(path.clone());
+            }
+            if wanted(&path) {
+                // unpack_in refuses entries that would land outside the destination.
+                entry.unpack_in(dest).with_context(|| format!("Could not unpack {0} into {1}", path, dest))?;
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
 
-        remove_file("./lc/BepInEx.tar.gz").context("Could not remove BepInEx.tar.gz")?;
-        remove_file("./lc/BepInEx.sha256").context("Could not remove BepInEx.sha256")?;
+    // Install BepInEx from an archive and finish setting it up. Returns the files the archive placed.
+    pub fn install_bepinex(&self, archive: &str) -> Result<Vec<String>> {
+        events::info(&format!("Unpacking BepInEx into {0}", self.lc_path));
+        // Configs kept from an earlier uninstall win over the archive's defaults.
+        let files = self.unpack_bepinex(archive, &self.lc_path, |path| !path.starts_with("BepInEx/config/") || !path_exists(&format!("{0}/{1}", self.lc_path, path)))?;
+
+        match self.bootstrap {
+            Bootstrap::Launch => self.first_launch()?,
+            Bootstrap::Layout => self.create_layout()?
+        }
+        Ok(files)
+    }
+
+    // Replace BepInEx's own files with another build, keeping plugins, configs and patchers. Returns the files in the new archive.
+    // The new build is unpacked into staging first and swapped in as a transaction, so a failure leaves the old build working.
+    pub fn upgrade_bepinex(&self, archive: &str) -> Result<Vec<String>> {
+        let mut transaction = Transaction::begin(&self.lc_path)?;
+        match self.swap_bepinex(archive, &mut transaction) {
+            Ok(files) => {
+                transaction.commit()?;
+                Ok(files)
+            }
+            Err(err) => {
+                transaction.rollback().context("Could not restore the previous BepInEx")?;
+                Err(err)
+            }
+        }
+    }
+
+    fn swap_bepinex(&self, archive: &str, transaction: &mut Transaction) -> Result<Vec<String>> {
+        let staging = transaction.staging_dir();
+        let files = self.unpack_bepinex(archive, &staging, |path| !KEPT_DIRS.iter().any(|kept| path == *kept || path.starts_with(&format!("{0}/", kept))))?;
+        // Files the old build had and the new one doesn't would otherwise be loaded alongside it.
+        transaction.remove(&format!("{0}/BepInEx/core", self.lc_path))?;
+        for entry in read_dir(&staging).with_context(|| format!("Could not read {0}", staging))? {
+            let name = entry.with_context(|| format!("Could not read an entry of {0}", staging))?.file_name();
+            let name = name.to_string_lossy();
+            transaction.place(&format!("{0}/{1}", staging, name), &format!("{0}/{1}", self.lc_path, name))?;
+        }
+        Ok(files)
+    }
 
+    // Restore the doorstop files that are missing from an archive, leaving everything else as it is.
+    pub fn repair_bepinex(&self, archive: &str, missing: &[String]) -> Result<()> {
+        let files = self.unpack_bepinex(archive, &self.lc_path, |path| missing.iter().any(|missing| missing == path))?;
+        if let Some(absent) = missing.iter().find(|missing| !files.contains(missing)) {
+            return Err(LCError::BepInExSetup(format!("the BepInEx archive has no {0} to restore", absent)).into());
+        }
+        Ok(())
+    }
+
+    // The files that load BepInEx into the game and aren't there.
+    pub fn missing_doorstop(&self) -> Vec<String> {
+        DOORSTOP_FILES.iter()
+            .filter(|file| !path_exists(&format!("{0}/{1}", self.lc_path, file)))
+            .map(|file| file.to_string())
+            .collect()
+    }
+
+    // The folders BepInEx would create on its first launch, so plugins can be installed without running the game.
+    fn create_layout(&self) -> Result<()> {
+        for folder in ["plugins", "config", "patchers"] {
+            let path = format!("{0}/BepInEx/{1}", self.lc_path, folder);
+            create_dir_all(&path).with_context(|| format!("Could not create {0}", path))?;
+        }
+        events::info("Created BepInEx/plugins, config and patchers, BepInEx finishes setting up the first time the game starts");
+        Ok(())
+    }
+
+    // Launch the game once through Steam and wait, up to the timeout, for BepInEx's preloader to run and set up its folders.
+    fn first_launch(&self) -> Result<()> {
+        // A log left by an earlier install would look like the preloader ran.
+        let log = format!("{0}/BepInEx/LogOutput.log", self.lc_path);
+        if path_exists(&log) {
+            remove_file(&log).with_context(|| format!("Could not remove {0}", log))?;
+        }
+
+        events::info("Launching Lethal Company through Steam so BepInEx can set itself up");
+        let url = format!("steam://rungameid/{0}", APP_ID);
         // If this is a flatpak install, we have to run steam via flatpak
-        if self.flatpak {
-            Command::new(&self.run_command)
-                        .args(["run", "com.valvesoftware.Steam", "steam://rungameid/1966720"])
-                        .spawn()
-                        .context("Could not run Lethal Company")?;
+        let launched = if self.flatpak {
+            Command::new(&self.run_command).args(["run", "com.valvesoftware.Steam", &url]).spawn()
         } else {
-            Command::new(&self.run_command)
-                        .arg("steam://rungameid/1966720")
-                        .spawn()
-                        .context("Could not run Lethal Company")?;
-        }
+            Command::new(&self.run_command).arg(&url).spawn()
+        };
+        launched.with_context(|| format!("Could not launch Lethal Company with {0}", self.run_command))?;
 
-        while !path_exists(&format!("{0}/BepInEx/plugins", self.lc_path)) {
-            std::thread::sleep(std::time::Duration::from_millis(100));
+        let plugins = format!("{0}/BepInEx/plugins", self.lc_path);
+        let start = Instant::now();
+        let mut preloader_ran = false;
+        let mut reported = Duration::ZERO;
+        while !path_exists(&plugins) {
+            if !preloader_ran && path_exists(&log) {
+                preloader_ran = true;
+                events::info("BepInEx's preloader is running");
+            }
+
+            let waited = start.elapsed();
+            if waited >= self.timeout {
+                let seconds = self.timeout.as_secs();
+                let reason = if preloader_ran {
+                    format!("BepInEx started but didn't create BepInEx/plugins within {0}s, see {1}", seconds, log)
+                } else if self.proton_roots.is_empty() {
+                    format!("BepInEx's preloader didn't run within {0}s of launching the game (no {1}), check that Steam is running and the game starts", seconds, log)
+                } else {
+                    format!("BepInEx's preloader didn't run within {0}s of launching the game (no {1}), check that Steam is running, the game starts and its launch options include {2}", seconds, log, WINHTTP_OVERRIDE)
+                };
+                return Err(LCError::BepInExSetup(format!("{0}; raise BEPINEXTIMEOUT or use --bepinex-bootstrap layout to skip the launch", reason)).into());
+            }
+            if waited - reported >= PROGRESS_INTERVAL {
+                reported = waited;
+                events::info(&format!("Waiting for BepInEx to set itself up ({0}s of {1}s)", waited.as_secs(), self.timeout.as_secs()));
+            }
+            std::thread::sleep(POLL_INTERVAL);
         }
 
+        events::info("BepInEx is set up");
         Ok(())
     }
-}
\ No newline at end of file
+}
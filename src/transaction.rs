@@ -0,0 +1,177 @@
+use anyhow::{Context, Result};
+use crate::events;
+use crate::util::{move_path, path_exists, LCError};
+use serde::{Serialize, Deserialize};
+use std::fs::{create_dir_all, metadata, read_dir, read_to_string, remove_dir_all, remove_file, write};
+use std::io::ErrorKind;
+
+// A change made to the game folder that can be undone.
+#[derive(Serialize, Deserialize)]
+enum Step {
+    // An existing path was moved into the backup area, or relocated.
+    Moved { original: String, backup: String },
+    // A path was created that did not exist before.
+    Created { path: String }
+}
+
+// Records every change made during a sync so it can be rolled back if any step fails.
+// The journal is written before each change, so a sync that was killed part way is rolled back on the next run.
+pub struct Transaction {
+    work_dir: String,
+    steps: Vec<Step>
+}
+
+// Working directory used for staging and backups. It lives in the game folder so moves are plain renames.
+pub fn work_dir(lc_path: &str) -> String {
+    format!("{0}/.lcmodmanager/transaction", lc_path)
+}
+
+impl Transaction {
+    // Roll back a transaction left over from an interrupted sync, if there is one.
+    pub fn recover(lc_path: &str) -> Result<()> {
+        let work_dir = work_dir(lc_path);
+        let journal = format!("{0}/journal.json", work_dir);
+        if path_exists(&journal) {
+            events::info("Restoring plugins from an interrupted sync...");
+            let journal_str = read_to_string(&journal).context("Could not read sync journal")?;
+            let steps: Vec<Step> = serde_json::from_str(&journal_str).context("Could not parse sync journal")?;
+            Transaction { work_dir: work_dir.clone(), steps }.rollback()?;
+        }
+
+        Ok(())
+    }
+
+    // Start a new transaction, rolling back any transaction left over from an interrupted sync first.
+    pub fn begin(lc_path: &str) -> Result<Transaction> {
+        Transaction::recover(lc_path)?;
+
+        let work_dir = work_dir(lc_path);
+        if path_exists(&work_dir) {
+            remove_dir_all(&work_dir).with_context(|| format!("Could not clear {0}", work_dir))?;
+        }
+        // The first write to the game folder, so not being allowed to write there is reported as such.
+        if let Err(err) = create_dir_all(format!("{0}/backup", work_dir)) {
+            if err.kind() == ErrorKind::PermissionDenied {
+                return Err(LCError::Permission(lc_path.to_string()).into());
+            }
+            return Err(err).context("Could not create backup directory");
+        }
+        create_dir_all(format!("{0}/staging", work_dir)).context("Could not create staging directory")?;
+
+        let transaction = Transaction { work_dir, steps: vec![] };
+        transaction.save()?;
+        Ok(transaction)
+    }
+
+    // Directory that downloads are unpacked into before being switched into place.
+    pub fn staging_dir(&self) -> String {
+        format!("{0}/staging", self.work_dir)
+    }
+
+    // Where whatever was at `path` before this transaction is now, if it was moved out of the way.
+    pub fn previous(&self, path: &str) -> Option<String> {
+        for step in &self.steps {
+            match step {
+                Step::Moved { original, backup } => {
+                    if let Some(rest) = path.strip_prefix(original.as_str()) {
+                        if rest.is_empty() || rest.starts_with('/') {
+                            let previous = format!("{0}{1}", backup, rest);
+                            return if path_exists(&previous) { Some(previous) } else { None };
+                        }
+                    }
+                }
+                // Created by this transaction before anything was moved, so there was nothing there.
+                Step::Created { path: created } => {
+                    if path == created || path.starts_with(&format!("{0}/", created)) {
+                        return None;
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn save(&self) -> Result<()> {
+        let journal = serde_json::to_string(&self.steps).context("Could not serialize sync journal")?;
+        write(format!("{0}/journal.json", self.work_dir), journal).context("Could not write sync journal")
+    }
+
+    fn record(&mut self, step: Step) -> Result<()> {
+        self.steps.push(step);
+        self.save()
+    }
+
+    // Move a path out of the way into the backup area, if it exists.
+    pub fn remove(&mut self, path: &str) -> Result<()> {
+        if !path_exists(path) {
+            return Ok(());
+        }
+
+        let backup = format!("{0}/backup/{1}", self.work_dir, self.steps.len());
+        self.record(Step::Moved { original: path.to_string(), backup: backup.clone() })?;
+        move_path(path, &backup).with_context(|| format!("Could not back up {0}", path))
+    }
+
+    // Move a path somewhere it is kept after the transaction, moving it back on rollback.
+    pub fn relocate(&mut self, from: &str, to: &str) -> Result<()> {
+        if !path_exists(from) {
+            return Ok(());
+        }
+
+        self.record(Step::Moved { original: from.to_string(), backup: to.to_string() })?;
+        move_path(from, to).with_context(|| format!("Could not move {0} to {1}", from, to))
+    }
+
+    // Create an empty directory.
+    pub fn create_dir(&mut self, path: &str) -> Result<()> {
+        self.record(Step::Created { path: path.to_string() })?;
+        create_dir_all(path).with_context(|| format!("Could not create {0}", path))
+    }
+
+    // Move a staged file or directory into place, backing up anything it replaces.
+    // Directories that already exist are merged into rather than replaced.
+    pub fn place(&mut self, staged: &str, dest: &str) -> Result<()> {
+        let staged_is_dir = metadata(staged).with_context(|| format!("Could not read {0}", staged))?.is_dir();
+        if staged_is_dir && metadata(dest).map(|dest| dest.is_dir()).unwrap_or(false) {
+            for entry in read_dir(staged).with_context(|| format!("Could not read {0}", staged))? {
+                let name = entry.context("Could not read staged entry")?.file_name();
+                let name = name.to_string_lossy();
+                self.place(&format!("{0}/{1}", staged, name), &format!("{0}/{1}", dest, name))?;
+            }
+            return Ok(());
+        }
+
+        self.remove(dest)?;
+        self.record(Step::Created { path: dest.to_string() })?;
+        move_path(staged, dest).with_context(|| format!("Could not move {0} into place", dest))
+    }
+
+    // Keep every change and throw away the backups.
+    pub fn commit(self) -> Result<()> {
+        remove_dir_all(&self.work_dir).with_context(|| format!("Could not remove {0}", self.work_dir))
+    }
+
+    // Undo every change in reverse order and restore the backups.
+    pub fn rollback(self) -> Result<()> {
+        for step in self.steps.iter().rev() {
+            match step {
+                Step::Created { path } => {
+                    if let Ok(created) = metadata(path) {
+                        if created.is_dir() {
+                            remove_dir_all(path).with_context(|| format!("Could not remove {0} during rollback", path))?;
+                        } else {
+                            remove_file(path).with_context(|| format!("Could not remove {0} during rollback", path))?;
+                        }
+                    }
+                }
+                Step::Moved { original, backup } => {
+                    if path_exists(backup) {
+                        move_path(backup, original).with_context(|| format!("Could not restore {0} during rollback", original))?;
+                    }
+                }
+            }
+        }
+
+        remove_dir_all(&self.work_dir).with_context(|| format!("Could not remove {0}", self.work_dir))
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{Context, Result};
+use crate::events;
+use crate::installed::{bepinex_db_path, db_path, InstallDb};
+use crate::transaction::Transaction;
+use crate::util::path_exists;
+use std::collections::BTreeSet;
+use std::fs::{read_dir, remove_dir, remove_dir_all, remove_file, symlink_metadata, File};
+use std::io::copy;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+// Files BepInEx puts next to the game, relative to the game folder.
+const BEPINEX_ROOT_FILES: [&str; 4] = ["winhttp.dll", "doorstop_config.ini", ".doorstop_version", "changelog.txt"];
+
+// Every file under `dir`, as paths relative to it prefixed with `prefix`. Symbolic links, such as a mod folder linked in
+// from elsewhere, are collected in `links` and not followed.
+fn walk(dir: &str, prefix: &str, files: &mut Vec<String>, links: &mut Vec<String>) -> Result<()> {
+    for entry in read_dir(dir).with_context(|| format!("Could not read {0}", dir))? {
+        let entry = entry.with_context(|| format!("Could not read an entry of {0}", dir))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = format!("{0}/{1}", dir, name);
+        let relative = format!("{0}{1}", prefix, name);
+        let meta = symlink_metadata(&path).with_context(|| format!("Could not read {0}", path))?;
+        if meta.is_symlink() {
+            links.push(relative);
+        } else if meta.is_dir() {
+            walk(&path, &format!("{0}/", relative), files, links)?;
+        } else {
+            files.push(relative);
+        }
+    }
+    Ok(())
+}
+
+// Remove a symbolic link, leaving what it points to alone. Windows links to directories are removed as directories.
+fn remove_link(path: &str) -> Result<()> {
+    remove_file(path).or_else(|_| remove_dir(path)).with_context(|| format!("Could not remove {0}", path))
+}
+
+// The files lcmodmanager placed and the player hasn't changed since, relative to the game folder.
+// User-editable defaults are the player's, so they are backed up rather than removed.
+fn placed(lc_path: &str, installed: &InstallDb) -> BTreeSet<String> {
+    let mut placed: BTreeSet<String> = BTreeSet::new();
+    for plugin in installed.plugins.values() {
+        for file in plugin.files.iter().filter(|file| !file.default) {
+            let path = format!("BepInEx/{0}", file.path);
+            if symlink_metadata(format!("{0}/{1}", lc_path, path)).is_ok_and(|meta| meta.is_file() && meta.len() == file.size) {
+                placed.insert(path);
+            }
+        }
+    }
+    placed.insert("BepInEx/plugins/plugins.json".to_string());
+    placed.insert("BepInEx/plugins/plugins.sha256".to_string());
+    // BepInEx's own configs are the player's to edit, like plugin defaults.
+    if let Some(bepinex) = &installed.bepinex {
+        placed.extend(bepinex.files.iter().filter(|file| !file.starts_with("BepInEx/config/")).cloned());
+    }
+    placed
+}
+
+// Write the files to a zip next to the game, named after the current time. Returns the zip's path.
+fn back_up(lc_path: &str, files: &[String]) -> Result<String> {
+    let zip_path = format!("{0}/lcmodmanager-backup-{1}.zip", lc_path, chrono::Local::now().format("%Y%m%d-%H%M%S"));
+    let mut zip = ZipWriter::new(File::create(&zip_path).with_context(|| format!("Could not create {0}", zip_path))?);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for file in files {
+        zip.start_file(file.as_str(), options).with_context(|| format!("Could not add {0} to {1}", file, zip_path))?;
+        let path = format!("{0}/{1}", lc_path, file);
+        let mut source = File::open(&path).with_context(|| format!("Could not open {0}", path))?;
+        copy(&mut source, &mut zip).with_context(|| format!("Could not add {0} to {1}", file, zip_path))?;
+    }
+    zip.finish().with_context(|| format!("Could not write {0}", zip_path))?;
+    Ok(zip_path)
+}
+
+// Remove BepInEx and the plugins using the install record. Files lcmodmanager placed are removed, anything else (configs, logs,
+// plugins installed by hand) is backed up to a zip first. Symbolic links are removed without touching or backing up what they
+// point to. With `keep_config`, BepInEx/config is left in place instead.
+pub fn uninstall(lc_path: &str, keep_config: bool) -> Result<()> {
+    Transaction::recover(lc_path)?;
+    let installed = InstallDb::load(lc_path);
+    let placed = placed(lc_path, &installed);
+
+    let mut files: Vec<String> = vec![];
+    let mut links: Vec<String> = vec![];
+    let bepinex = format!("{0}/BepInEx", lc_path);
+    match symlink_metadata(&bepinex) {
+        Ok(meta) if meta.is_symlink() => links.push("BepInEx".to_string()),
+        Ok(_) => walk(&bepinex, "BepInEx/", &mut files, &mut links)?,
+        Err(_) => {}
+    }
+    for file in BEPINEX_ROOT_FILES {
+        match symlink_metadata(format!("{0}/{1}", lc_path, file)) {
+            Ok(meta) if meta.is_symlink() => links.push(file.to_string()),
+            Ok(_) => files.push(file.to_string()),
+            Err(_) => {}
+        }
+    }
+    let kept = |file: &String| keep_config && (file == "BepInEx/config" || file.starts_with("BepInEx/config/"));
+    let unrecognised: Vec<String> = files.iter().filter(|file| !kept(file) && !placed.contains(*file)).cloned().collect();
+
+    // Nothing is removed until everything that would be lost is safely in the backup.
+    if !unrecognised.is_empty() {
+        let zip_path = back_up(lc_path, &unrecognised)?;
+        events::info(&format!("Backed up {0} files lcmodmanager didn't install to {1}", unrecognised.len(), zip_path));
+    }
+
+    for link in links.iter().filter(|link| !kept(link)) {
+        remove_link(&format!("{0}/{1}", lc_path, link))?;
+    }
+    for file in files.iter().filter(|file| !kept(file)) {
+        let path = format!("{0}/{1}", lc_path, file);
+        remove_file(&path).with_context(|| format!("Could not remove {0}", path))?;
+    }
+    if path_exists(&bepinex) {
+        if keep_config {
+            // Only the config folder is left, everything else is now empty directories.
+            for entry in read_dir(&bepinex).with_context(|| format!("Could not read {0}", bepinex))? {
+                let entry = entry.with_context(|| format!("Could not read an entry of {0}", bepinex))?;
+                if entry.file_name() != "config" {
+                    remove_dir_all(entry.path()).with_context(|| format!("Could not remove {0}", entry.path().display()))?;
+                }
+            }
+        } else {
+            remove_dir_all(&bepinex).with_context(|| format!("Could not remove {0}", bepinex))?;
+        }
+    }
+
+    // Player preferences such as selections and profiles stay, the record of what was installed doesn't.
+    for record in [db_path(lc_path), bepinex_db_path(lc_path)] {
+        if path_exists(&record) {
+            remove_file(&record).with_context(|| format!("Could not remove install database: {0}", record))?;
+        }
+    }
+    let defaults = format!("{0}/.lcmodmanager/defaults", lc_path);
+    if path_exists(&defaults) {
+        remove_dir_all(&defaults).with_context(|| format!("Could not remove {0}", defaults))?;
+    }
+
+    if keep_config {
+        events::info("Removed BepInEx and its plugins, BepInEx/config was kept");
+    } else {
+        events::info("Removed BepInEx and its plugins");
+    }
+    Ok(())
+}
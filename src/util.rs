@@ -1,45 +1,159 @@
 use anyhow::{Context, Result};
+use crate::events;
+use crate::installed::{bepinex_db_path, db_path};
 use data_encoding::HEXLOWER;
 use ring::digest;
-use std::fs::{File, metadata, remove_dir_all, remove_file};
-use std::io::{BufReader, Read};
+use fs_extra::dir::{CopyOptions, move_dir};
+use std::fs::{File, copy, metadata, remove_dir_all, remove_file, rename};
+use reqwest::blocking::get;
+use std::io::{stdin, stdout, BufReader, ErrorKind, Read, Write};
 use thiserror::Error;
 
+// Every failure a sync can run into that a wrapper script might want to react to. Each kind exits with its own code.
 #[derive(Error, Debug)]
 pub enum LCError {
-    #[error("checksum discrepency")]
-    CheckSumDiscrepency(String)
+    // Conflicting flags or invalid settings.
+    #[error("{0}")]
+    Usage(String),
+    #[error("could not reach {url}")]
+    Network {
+        url: String,
+        #[source]
+        source: reqwest::Error
+    },
+    #[error("{name} doesn't match its sha256\nServer: {expected}\nDownload: {actual}")]
+    CheckSumDiscrepency {
+        name: String,
+        expected: String,
+        actual: String
+    },
+    #[error("invalid manifest signature: {0}")]
+    SignatureInvalid(String),
+    #[error("invalid plugins.json: {0}")]
+    ManifestParse(String),
+    #[error("{0}, please update lcmodmanager (lcmodmanager self-update)")]
+    UnsupportedManifest(String),
+    #[error("{0}")]
+    Dependency(String),
+    #[error("permission denied writing to {0}")]
+    Permission(String),
+    #[error("Lethal Company was not found at {0}, set its path with --lethal-company-path or the config file")]
+    GameNotFound(String),
+    #[error("BepInEx is not installed at {0}")]
+    BepInExMissing(String),
+    // The step of a fresh BepInEx install that didn't complete.
+    #[error("BepInEx setup failed: {0}")]
+    BepInExSetup(String),
+    // The player declined, or a policy refused, a change.
+    #[error("{0}")]
+    Cancelled(String)
 }
 
-// Returns true if BepInEx is installed
+// Exit code for failures that don't fall into any LCError kind.
+const EXIT_OTHER: u8 = 1;
+const EXIT_NETWORK: u8 = 3;
+const EXIT_PERMISSION: u8 = 9;
+
+impl LCError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            LCError::Usage(_) => 2,
+            LCError::Network { .. } => EXIT_NETWORK,
+            LCError::CheckSumDiscrepency { .. } => 4,
+            LCError::SignatureInvalid(_) => 5,
+            LCError::ManifestParse(_) => 6,
+            LCError::UnsupportedManifest(_) => 7,
+            LCError::Dependency(_) => 8,
+            LCError::Permission(_) => EXIT_PERMISSION,
+            LCError::GameNotFound(_) => 10,
+            LCError::BepInExMissing(_) | LCError::BepInExSetup(_) => 11,
+            LCError::Cancelled(_) => 12
+        }
+    }
+}
+
+// The exit code for an error, from the first LCError behind it. Network and permission failures that were never wrapped
+// in one are recognised from the underlying error.
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<LCError>() {
+            return err.exit_code();
+        }
+        if cause.is::<reqwest::Error>() {
+            return EXIT_NETWORK;
+        }
+        if cause.downcast_ref::<std::io::Error>().is_some_and(|err| err.kind() == ErrorKind::PermissionDenied) {
+            return EXIT_PERMISSION;
+        }
+    }
+    EXIT_OTHER
+}
+
+// Download a small file, such as a manifest or checksum, into memory.
+pub fn get_bytes(url: &str) -> std::result::Result<Vec<u8>, LCError> {
+    get(url).and_then(|resp| resp.error_for_status()).and_then(|resp| resp.bytes())
+        .map(|body| body.to_vec())
+        .map_err(|source| LCError::Network { url: url.to_string(), source })
+}
+
+// Ask the player something and return their trimmed answer, or None if stdin is closed.
+// With JSON output the question goes to stderr, stdout only carries events.
+pub fn prompt(question: &str) -> Result<Option<String>> {
+    if events::json() {
+        eprint!("{0} ", question);
+    } else {
+        print!("{0} ", question);
+        stdout().flush().context("Could not write to stdout")?;
+    }
+    let mut answer = String::new();
+    if stdin().read_line(&mut answer).context("Could not read answer")? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(answer.trim().to_string()))
+}
+
+// Ask a yes or no question, anything but yes is a no.
+pub fn confirm(question: &str) -> Result<bool> {
+    let answer = prompt(&format!("{0} [y/N]", question))?.unwrap_or_default();
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+// Returns true if BepInEx is installed, a BepInEx folder holding only kept configs isn't an install
 pub fn check_bepinex(path: &str) -> bool {
-    path_exists(&format!("{0}/BepInEx", path))
+    path_exists(&format!("{0}/BepInEx/core", path))
 }
 
-// Delete's BepInEx if it is present
-pub fn uninstall(path: &str) -> Result<()> {
+// Delete's BepInEx if it is present, along with everything in it
+pub fn purge(path: &str) -> Result<()> {
     let bep_in_ex = &format!("{0}/BepInEx", path);
-    if path_exists(&bep_in_ex) {
-        remove_dir_all(&bep_in_ex)
+    if path_exists(bep_in_ex) {
+        remove_dir_all(bep_in_ex)
             .with_context(|| format!("Could not remove BepInEx: {0}", bep_in_ex))?;
     }
 
     let win_http = &format!("{0}/winhttp.dll", path);
-    if path_exists(&win_http) {
-        remove_file(&win_http)
+    if path_exists(win_http) {
+        remove_file(win_http)
             .with_context(|| format!("Could not remove winhttp.dll: {0}", win_http))?;
     }
 
     let doorstop_config = &format!("{0}/doorstop_config.ini", path);
-    if path_exists(&doorstop_config) {
-        remove_file(&doorstop_config)
+    if path_exists(doorstop_config) {
+        remove_file(doorstop_config)
             .with_context(|| format!("Could not remove doorstop_config.ini: {0}", doorstop_config))?;
     }
 
-    let changelog = &format!("{0}/changelog.txt ", path);
-    if path_exists(&changelog) {
-        remove_file(&changelog)
-            .with_context(|| format!("Could not remove changelog.txt : {0}", changelog))?;
+    for install_db in [db_path(path), bepinex_db_path(path)] {
+        if path_exists(&install_db) {
+            remove_file(&install_db)
+                .with_context(|| format!("Could not remove install database: {0}", install_db))?;
+        }
+    }
+
+    let changelog = &format!("{0}/changelog.txt", path);
+    if path_exists(changelog) {
+        remove_file(changelog)
+            .with_context(|| format!("Could not remove changelog.txt: {0}", changelog))?;
     }
 
     Ok(())
@@ -50,6 +164,24 @@ pub fn path_exists(path: &str) -> bool {
     metadata(path).is_ok()
 }
 
+// Moves a file or directory, falling back to copy and delete when the destination is on another filesystem
+pub fn move_path(from: &str, to: &str) -> Result<()> {
+    if rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    if metadata(from).with_context(|| format!("Could not read {0}", from))?.is_dir() {
+        let mut options = CopyOptions::new();
+        options.copy_inside = true;
+        move_dir(from, to, &options).with_context(|| format!("Could not move {0} to {1}", from, to))?;
+    } else {
+        copy(from, to).with_context(|| format!("Could not copy {0} to {1}", from, to))?;
+        remove_file(from).with_context(|| format!("Could not remove {0}", from))?;
+    }
+
+    Ok(())
+}
+
 // Modified from:
 // https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html
 fn sha256_digest<R: Read>(mut reader: R) -> Result<digest::Digest> {
@@ -73,4 +205,15 @@ pub fn sha256_sum(path: &str) -> Result<String> {
     let digest = sha256_digest(reader)?;
 
     Ok(HEXLOWER.encode(digest.as_ref()))
-}
\ No newline at end of file
+}
+
+// Expands a leading ~ to the user's home directory
+pub fn expand_home(path: &str) -> Result<String> {
+    match path.strip_prefix('~') {
+        Some(rest) => {
+            let home = std::env::var("HOME").map_err(|_| LCError::Usage(format!("HOME isn't set, export it (e.g. export HOME=/home/user) or use a full path instead of {0}", path)))?;
+            Ok(format!("{0}{1}", home, rest))
+        }
+        None => Ok(path.to_string())
+    }
+}
@@ -0,0 +1,187 @@
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::fs::read_to_string;
+
+// A node of Valve's KeyValues text format, used by libraryfolders.vdf, app manifests and localconfig.vdf.
+pub enum Vdf {
+    Value(String),
+    Object(Vec<(String, Vdf)>)
+}
+
+impl Vdf {
+    // Look up a key, case-insensitively like Steam does.
+    pub fn get(&self, key: &str) -> Option<&Vdf> {
+        match self {
+            Vdf::Object(entries) => entries.iter().find(|(name, _)| name.eq_ignore_ascii_case(key)).map(|(_, value)| value),
+            Vdf::Value(_) => None
+        }
+    }
+
+    // The object under a key. Returns None if it is missing, this isn't an object or the key holds a value.
+    pub fn object_mut(&mut self, key: &str) -> Option<&mut Vdf> {
+        let Vdf::Object(entries) = self else { return None };
+        match entries.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case(key)) {
+            Some((_, object @ Vdf::Object(_))) => Some(object),
+            _ => None
+        }
+    }
+
+    // Set a key to a value, replacing whatever it held. Does nothing if this isn't an object.
+    pub fn set(&mut self, key: &str, value: &str) {
+        let Vdf::Object(entries) = self else { return };
+        match entries.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case(key)) {
+            Some((_, existing)) => *existing = Vdf::Value(value.to_string()),
+            None => entries.push((key.to_string(), Vdf::Value(value.to_string())))
+        }
+    }
+
+    pub fn value(&self) -> Option<&str> {
+        match self {
+            Vdf::Value(value) => Some(value),
+            Vdf::Object(_) => None
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        let Vdf::Object(entries) = self else { return Ok(()) };
+        let indent = "\t".repeat(depth);
+        for (key, value) in entries {
+            match value {
+                Vdf::Value(value) => writeln!(f, "{0}\"{1}\"\t\t\"{2}\"", indent, escape(key), escape(value))?,
+                Vdf::Object(_) => {
+                    writeln!(f, "{0}\"{1}\"", indent, escape(key))?;
+                    writeln!(f, "{0}{{", indent)?;
+                    value.write(f, depth + 1)?;
+                    writeln!(f, "{0}}}", indent)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Written the way Steam writes its own files, so the whole document can be saved back.
+impl fmt::Display for Vdf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\t', "\\t")
+}
+
+enum Token {
+    Open,
+    Close,
+    Text(String)
+}
+
+// Split KeyValues text into quoted strings and braces, skipping // comments. Anything else, such as unquoted strings or
+// conditionals, isn't understood, and the file is refused rather than misread.
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens: Vec<Token> = vec![];
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => tokens.push(Token::Open),
+            '}' => tokens.push(Token::Close),
+            '"' => {
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => token.push('\n'),
+                            Some('t') => token.push('\t'),
+                            Some(escaped) => token.push(escaped),
+                            None => bail!("unterminated string on line {0}", line)
+                        },
+                        Some(other) => {
+                            if other == '\n' {
+                                line += 1;
+                            }
+                            token.push(other)
+                        }
+                        None => bail!("unterminated string on line {0}", line)
+                    }
+                }
+                tokens.push(Token::Text(token));
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+            }
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            other => bail!("unexpected {0:?} on line {1}", other, line)
+        }
+    }
+    Ok(tokens)
+}
+
+// Parse key/value pairs until the closing brace of the current object, or the end of the text at the top level.
+fn parse_object(tokens: &mut std::vec::IntoIter<Token>, nested: bool) -> Result<Vdf> {
+    let mut entries: Vec<(String, Vdf)> = vec![];
+    loop {
+        let key = match tokens.next() {
+            Some(Token::Text(key)) => key,
+            Some(Token::Close) if nested => break,
+            Some(_) => bail!("expected a key"),
+            None if nested => bail!("missing a closing brace"),
+            None => break
+        };
+        match tokens.next() {
+            Some(Token::Open) => {
+                let object = parse_object(tokens, true)?;
+                entries.push((key, object));
+            }
+            Some(Token::Text(value)) => entries.push((key, Vdf::Value(value))),
+            _ => bail!("{0} has no value", key)
+        }
+    }
+    Ok(Vdf::Object(entries))
+}
+
+pub fn parse(text: &str) -> Result<Vdf> {
+    parse_object(&mut tokenize(text)?.into_iter(), false)
+}
+
+pub fn load(path: &str) -> Result<Vdf> {
+    let text = read_to_string(path).with_context(|| format!("Could not read {0}", path))?;
+    parse(&text).with_context(|| format!("Could not parse {0}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text = "\"UserLocalConfigStore\"\n{\n\t// comment\n\t\"apps\"\n\t{\n\t\t\"1966720\"\n\t\t{\n\t\t\t\"LaunchOptions\"\t\t\"a \\\"b\\\" c\\\\d\\ne\\tf\"\n\t\t}\n\t}\n\t\"empty\"\t\t\"\"\n}\n";
+        let vdf = parse(text).unwrap();
+        let options = ["UserLocalConfigStore", "apps", "1966720", "LaunchOptions"].iter()
+            .try_fold(&vdf, |node, key| node.get(key))
+            .and_then(Vdf::value);
+        assert_eq!(options, Some("a \"b\" c\\d\ne\tf"));
+
+        let written = vdf.to_string();
+        assert_eq!(written, text.replace("\t// comment\n", ""));
+        assert_eq!(parse(&written).unwrap().to_string(), written);
+    }
+
+    #[test]
+    fn refuses_what_it_cant_read() {
+        assert!(parse("\"a\" { \"b\" unquoted }").is_err());
+        assert!(parse("\"a\" { \"b\" \"c\" [$WIN32] }").is_err());
+        assert!(parse("\"a\" { \"b\" \"c\"").is_err());
+        assert!(parse("\"a\" \"b").is_err());
+        assert!(parse("\"a\" }").is_err());
+        assert!(parse("\"a\"").is_err());
+    }
+}
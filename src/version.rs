@@ -0,0 +1,67 @@
+use semver::Version;
+use std::cmp::Ordering;
+
+// Parses a plugin version as semver. Versions that aren't, such as "1.2", "v1.2.3.4" or "1.0b", fall back to their
+// leading numbers, and anything without one can't be parsed.
+pub fn parse(version: &str) -> Option<Version> {
+    let version = version.trim().trim_start_matches(['v', 'V']);
+    if let Ok(parsed) = Version::parse(version) {
+        return Some(parsed);
+    }
+
+    let mut numbers: Vec<u64> = vec![];
+    for part in version.split('.').take(3) {
+        let digits: String = part.chars().take_while(|c| c.is_ascii_digit()).collect();
+        let Ok(number) = digits.parse() else { break };
+        numbers.push(number);
+        if digits.len() != part.len() {
+            break;
+        }
+    }
+
+    match numbers[..] {
+        [] => None,
+        [major] => Some(Version::new(major, 0, 0)),
+        [major, minor] => Some(Version::new(major, minor, 0)),
+        [major, minor, patch, ..] => Some(Version::new(major, minor, patch))
+    }
+}
+
+// How two plugin versions compare, or None if either can't be parsed and they differ.
+pub fn compare(a: &str, b: &str) -> Option<Ordering> {
+    if a == b {
+        return Some(Ordering::Equal);
+    }
+    Some(parse(a)?.cmp_precedence(&parse(b)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_semver() {
+        assert_eq!(parse("1.2.3"), Some(Version::new(1, 2, 3)));
+        assert_eq!(parse("1.2.3-beta.1").map(|version| version.pre.to_string()), Some("beta.1".to_string()));
+    }
+
+    #[test]
+    fn falls_back_to_leading_numbers() {
+        assert_eq!(parse("1.2"), Some(Version::new(1, 2, 0)));
+        assert_eq!(parse("v1.2.3.4"), Some(Version::new(1, 2, 3)));
+        assert_eq!(parse("1.0b"), Some(Version::new(1, 0, 0)));
+        assert_eq!(parse("7"), Some(Version::new(7, 0, 0)));
+        assert_eq!(parse("beta"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn compares() {
+        assert_eq!(compare("1.10.0", "1.9.0"), Some(Ordering::Greater));
+        assert_eq!(compare("1.2", "1.2.1"), Some(Ordering::Less));
+        assert_eq!(compare("v1.2.3", "1.2.3"), Some(Ordering::Equal));
+        assert_eq!(compare("1.0.0-rc.1", "1.0.0"), Some(Ordering::Less));
+        assert_eq!(compare("beta", "beta"), Some(Ordering::Equal));
+        assert_eq!(compare("beta", "1.0.0"), None);
+    }
+}